@@ -0,0 +1,270 @@
+//! A stand-in for a camera, for running the server (and everything on top of it) on machines
+//! without one. Frames are a gray ramp scrolling sideways, in whatever format was asked for.
+//! With auto exposure off, exposure time and gain make it brighter or darker.
+
+use super::{check_control, closest_size, Backend, CameraInfo, Capture, CaptureConfig};
+use crate::parser::{FakeArgs, FourCC};
+use crate::shared::*;
+use vistream_protocol::camera::{ControlInfo, ControlValue};
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// The exposure time (in microseconds) at which the ramp is as bright as it is with auto
+// exposure on.
+const NOMINAL_EXPOSURE: i64 = 10_000;
+
+/// Roughly what a Pi camera module takes, so code written against the fake does about the
+/// same thing on the real one.
+fn fake_controls() -> Vec<ControlInfo> {
+    use ControlValue::*;
+    let control = |name: &str, min, max, default| ControlInfo {
+        name: name.to_string(),
+        min,
+        max,
+        default: Some(default),
+    };
+    vec![
+        control("AeEnable", None, None, Bool(true)),
+        control("ExposureTime", Some(Int(100)), Some(Int(1_000_000)), Int(NOMINAL_EXPOSURE)),
+        control("AnalogueGain", Some(Float(1.0)), Some(Float(16.0)), Float(1.0)),
+        control("AwbEnable", None, None, Bool(true)),
+        control("AfMode", Some(Int(0)), Some(Int(2)), Int(0)),
+        control("LensPosition", Some(Float(0.0)), Some(Float(15.0)), Float(1.0)),
+        control("Brightness", Some(Float(-1.0)), Some(Float(1.0)), Float(0.0)),
+        control("Contrast", Some(Float(0.0)), Some(Float(32.0)), Float(1.0)),
+    ]
+}
+
+pub struct Fake {
+    cameras: usize,
+    formats: Vec<FourCC>,
+    sizes: Vec<(u32, u32)>,
+    framerate: f64,
+    fail_open: bool,
+    fail_after: Option<usize>,
+}
+
+impl Fake {
+    pub fn new(args: &FakeArgs) -> Fake {
+        Fake {
+            cameras: args.fake_cameras,
+            formats: args.fake_formats.clone(),
+            sizes: args.fake_sizes.clone(),
+            framerate: args.fake_framerate,
+            fail_open: args.fake_fail_open,
+            fail_after: args.fake_fail_after,
+        }
+    }
+}
+
+impl Backend for Fake {
+    fn cameras(&self) -> VisResult<Vec<CameraInfo>> {
+        Ok((0..self.cameras).map(|i| CameraInfo {
+            name: "Fake Camera".to_string(),
+            id: format!("fake/{}", i),
+        }).collect())
+    }
+
+    fn capture(
+        &self,
+        id: &str,
+        config: &CaptureConfig,
+        serve: &mut dyn FnMut(&mut dyn Capture) -> VisResult<()>,
+    ) -> VisResult<()> {
+        if !self.cameras()?.iter().any(|cam| cam.id == id) {
+            fail!(6, "no camera exists with id '{}'", id);
+        }
+        if self.fail_open {
+            fail!(6, "failed to acquire {} (--fake-fail-open)", id);
+        }
+        let streams = match pick_streams(&self.formats, &self.sizes, config) {
+            Ok(streams) => streams,
+            Err(e) => {
+                fail!(10, "{} for {}", e, id);
+            }
+        };
+
+        let controls = fake_controls();
+        let values = controls.iter().filter_map(|c| Some((c.name.clone(), c.default?))).collect();
+        serve(&mut FakeCapture {
+            formats: self.formats.clone(),
+            sizes: self.sizes.clone(),
+            controls,
+            values,
+            streams,
+            max_framerate: self.framerate,
+            interval: frame_interval(self.framerate, config),
+            next_due: Instant::now(),
+            frames: 0,
+            fail_after: self.fail_after,
+        })
+    }
+}
+
+/// Time between frames, at the requested framerate if the fake sensor can keep up.
+fn frame_interval(max_framerate: f64, config: &CaptureConfig) -> Duration {
+    let framerate = config.framerate.map_or(max_framerate, |f| f.min(max_framerate));
+    Duration::from_secs_f64(1.0 / framerate.max(0.001))
+}
+
+/// A stream's format and (width, height).
+type FakeStream = (FourCC, (u32, u32));
+
+/// The format and size of each stream a capture with `config` would have.
+fn pick_streams(formats: &[FourCC], sizes: &[(u32, u32)], config: &CaptureConfig) -> Result<Vec<FakeStream>, String> {
+    config.streams.iter().map(|stream| {
+        // There's no jpeg encoder here to make MJPG with.
+        if stream.format == FourCC::MJPG || !formats.contains(&stream.format) {
+            return Err(format!("{:?} is unsupported", stream.format));
+        }
+        let size = closest_size(sizes.to_vec(), stream.width, stream.height)
+            .ok_or_else(|| format!("{:?} is unsupported", stream.format))?;
+        Ok((stream.format, size))
+    }).collect()
+}
+
+struct FakeCapture {
+    formats: Vec<FourCC>,
+    sizes: Vec<(u32, u32)>,
+    controls: Vec<ControlInfo>,
+    values: HashMap<String, ControlValue>,
+    streams: Vec<FakeStream>,
+    // the most the fake sensor can do, whatever's asked for
+    max_framerate: f64,
+    interval: Duration,
+    next_due: Instant,
+    frames: usize,
+    fail_after: Option<usize>,
+}
+
+impl FakeCapture {
+    /// How much brighter than with auto exposure the frame should be.
+    fn brightness(&self) -> f64 {
+        if self.values.get("AeEnable") != Some(&ControlValue::Bool(false)) {
+            return 1.0;
+        }
+        let exposure = match self.values.get("ExposureTime") {
+            Some(ControlValue::Int(v)) => *v,
+            _ => NOMINAL_EXPOSURE,
+        };
+        let gain = match self.values.get("AnalogueGain") {
+            Some(ControlValue::Float(v)) => *v as f64,
+            _ => 1.0,
+        };
+        exposure as f64 / NOMINAL_EXPOSURE as f64 * gain
+    }
+
+    fn render(&self, format: FourCC, size: (u32, u32)) -> Vec<u8> {
+        let width = size.0 as usize;
+        let height = size.1 as usize;
+        let shift = self.frames * 4;
+        let brightness = self.brightness();
+        let level = |x: usize| (((x + shift) % width * 256 / width) as f64 * brightness).min(255.0) as u8;
+
+        let mut row = Vec::new();
+        match format {
+            FourCC::RG24 | FourCC::BG24 => {
+                for x in 0..width {
+                    row.extend_from_slice(&[level(x); 3]);
+                }
+            }
+            FourCC::RA24 | FourCC::BA24 => {
+                for x in 0..width {
+                    let v = level(x);
+                    row.extend_from_slice(&[v, v, v, 255]);
+                }
+            }
+            FourCC::YUYV => {
+                for x in (0..width).step_by(2) {
+                    row.extend_from_slice(&[level(x), 128, level(x + 1), 128]);
+                }
+            }
+            FourCC::RGGB => row.extend((0..width).map(level)),
+            FourCC::RG10 | FourCC::RG12 => {
+                let shift = if format == FourCC::RG10 { 2 } else { 4 };
+                for x in 0..width {
+                    row.extend_from_slice(&((level(x) as u16) << shift).to_le_bytes());
+                }
+            }
+            // The packed formats put the high 8 bits of each sample first, and the leftover
+            // low bits (all zero here) in one byte after every group.
+            FourCC::PRAA => {
+                for x in (0..width).step_by(4) {
+                    row.extend((x..x + 4).map(level));
+                    row.push(0);
+                }
+            }
+            FourCC::PRCC => {
+                for x in (0..width).step_by(2) {
+                    row.extend((x..x + 2).map(level));
+                    row.push(0);
+                }
+            }
+            FourCC::MJPG => unreachable!(),
+        }
+        row.repeat(height)
+    }
+}
+
+impl Capture for FakeCapture {
+    fn channels(&self) -> usize {
+        self.streams.len()
+    }
+
+    fn format(&self, channel: usize) -> FourCC {
+        self.streams[channel].0
+    }
+
+    fn size(&self, channel: usize) -> (u32, u32) {
+        self.streams[channel].1
+    }
+
+    fn framerate(&self) -> Option<f64> {
+        Some(1.0 / self.interval.as_secs_f64())
+    }
+
+    fn next_frame(&mut self, timeout: Duration, wanted: &[bool]) -> VisResult<Option<Vec<Option<Vec<u8>>>>> {
+        let now = Instant::now();
+        if now < self.next_due {
+            std::thread::sleep(timeout.min(self.next_due - now));
+            if Instant::now() < self.next_due {
+                return Ok(None);
+            }
+        }
+        if self.fail_after.is_some_and(|n| self.frames >= n) {
+            fail!(9, "camera disconnected (--fake-fail-after)");
+        }
+
+        // Like a real camera, frames that weren't picked up in time are just gone.
+        self.next_due += self.interval;
+        if self.next_due < now {
+            self.next_due = now + self.interval;
+        }
+        let data = self.streams.iter().zip(wanted).map(|(&(format, size), &wanted)| {
+            wanted.then(|| self.render(format, size))
+        }).collect();
+        self.frames += 1;
+        Ok(Some(data))
+    }
+
+    fn idle(&mut self) -> VisResult<()> {
+        Ok(())
+    }
+
+    fn controls(&self) -> Vec<ControlInfo> {
+        self.controls.clone()
+    }
+
+    fn set_control(&mut self, name: &str, value: ControlValue) -> Result<(), String> {
+        check_control(&self.controls, name, value)?;
+        self.values.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    fn reconfigure(&mut self, config: &CaptureConfig) -> Result<(), String> {
+        self.streams = pick_streams(&self.formats, &self.sizes, config)?;
+        self.interval = frame_interval(self.max_framerate, config);
+        Ok(())
+    }
+}
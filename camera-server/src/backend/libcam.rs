@@ -0,0 +1,403 @@
+use super::{check_control, closest_size, Backend, CameraInfo, Capture, CaptureConfig};
+use crate::parser::FourCC;
+use crate::shared::*;
+use vistream_protocol::camera::{ControlInfo, ControlValue, PixelFormat};
+
+use libcamera::{
+    camera::{ActiveCamera, CameraConfiguration, CameraConfigurationStatus},
+    camera_manager::CameraManager,
+    control::{Control, ControlList},
+    control_value::ControlValue as LcControlValue,
+    controls,
+    framebuffer::AsFrameBuffer,
+    framebuffer_allocator::{FrameBuffer, FrameBufferAllocator},
+    framebuffer_map::MemoryMappedFrameBuffer,
+    geometry::Size,
+    pixel_format as pf,
+    properties,
+    request::{Request, ReuseFlag},
+    stream::{Stream, StreamRole},
+};
+
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::time::Duration;
+
+pub struct Libcamera;
+
+impl Backend for Libcamera {
+    fn cameras(&self) -> VisResult<Vec<CameraInfo>> {
+        let mgr = unwrap_or_fail!(4, CameraManager::new());
+        let cameras = mgr.cameras();
+        Ok((0..cameras.len()).filter_map(|i| cameras.get(i)).map(|cam| CameraInfo {
+            name: cam.properties().get::<properties::Model>().unwrap().to_string(),
+            id: cam.id().to_string(),
+        }).collect())
+    }
+
+    fn capture(
+        &self,
+        id: &str,
+        config: &CaptureConfig,
+        serve: &mut dyn FnMut(&mut dyn Capture) -> VisResult<()>,
+    ) -> VisResult<()> {
+        let mgr = unwrap_or_fail!(4, CameraManager::new());
+        let cameras = mgr.cameras();
+        let Some(cam) = (0..cameras.len()).filter_map(|i| cameras.get(i)).find(|cam| cam.id() == id) else {
+            fail!(6, "no camera exists with id '{}'", id);
+        };
+        let mut cam = unwrap_or_fail!(6, cam.acquire());
+
+        let mut cfgs = match generate_config(&cam, config) {
+            Ok(cfgs) => cfgs,
+            Err((code, e)) => {
+                fail!(code, "{} ({})", e, id);
+            }
+        };
+
+        // Completed capture requests are returned as a callback
+        let (tx, rx) = mpsc::channel();
+        cam.on_request_completed(move |req| {
+            // The receiver only goes away once the camera's being torn down anyway.
+            let _ = tx.send(req);
+        });
+
+        let (streaming, reqs) = match start_streaming(&mut cam, &mut cfgs, config.framerate) {
+            Ok(started) => started,
+            Err((code, e)) => {
+                fail!(code, e);
+            }
+        };
+
+        serve(&mut LibcameraCapture {
+            rx,
+            unused_reqs: reqs,
+            streaming: Some(streaming),
+            frame_duration: None,
+            applied: Vec::new(),
+            pending: Vec::new(),
+            config: config.clone(),
+            cam,
+        })
+    }
+}
+
+/// Generates a configuration for `config`, without touching the camera itself, so it can
+/// be done while running. Errors come with the exit code they'd get at startup.
+fn generate_config(cam: &ActiveCamera, config: &CaptureConfig) -> Result<CameraConfiguration, (u8, String)> {
+    let roles = config.streams.iter().map(|stream| translate_role(stream.role)).collect::<Vec<_>>();
+    let Some(mut cfgs) = cam.generate_configuration(&roles) else {
+        return Err((10, "camera can't produce that combination of streams".to_string()));
+    };
+
+    for (i, stream) in config.streams.iter().enumerate() {
+        let mut cfg = cfgs.get_mut(i).unwrap();
+        let format = translate_pixel_format(stream.format);
+        cfg.set_pixel_format(format);
+        let sizes = cfg.formats().sizes(format).into_iter().map(|s| (s.width, s.height)).collect();
+        match closest_size(sizes, stream.width, stream.height) {
+            Some((width, height)) => cfg.set_size(Size { width, height }),
+            None => return Err((10, format!("{:?} is unsupported for channel {}", stream.format, i))),
+        };
+        cfg.set_buffer_count(config.buffer_count);
+    }
+
+    match cfgs.validate() {
+        CameraConfigurationStatus::Valid => {/* true no-op */}
+        // Sizes are read back once it's running, but frames are converted from the format
+        // that was asked for, so that has to have stuck.
+        CameraConfigurationStatus::Adjusted => {
+            for (i, stream) in config.streams.iter().enumerate() {
+                let wanted = translate_pixel_format(stream.format);
+                let actual = cfgs.get(i).unwrap().get_pixel_format();
+                if actual != wanted {
+                    return Err((10, format!("camera can't produce {:?} on channel {} (it offered {:?})", stream.format, i, actual)));
+                }
+            }
+        }
+        CameraConfigurationStatus::Invalid => {
+            return Err((8, "valid camera config could not be generated".to_string()));
+        },
+    };
+    Ok(cfgs)
+}
+
+/// Everything that has to be redone when the camera is reconfigured.
+struct Streaming {
+    // one per channel
+    streams: Vec<Stream>,
+    sizes: Vec<(u32, u32)>,
+    // The buffers belong to this, so it has to outlive the requests using them.
+    _alloc: FrameBufferAllocator,
+}
+
+/// Applies a configuration to a stopped camera, allocates buffers and requests for it, and
+/// starts it, at no more than `framerate` if there is one.
+fn start_streaming(
+    cam: &mut ActiveCamera,
+    cfgs: &mut CameraConfiguration,
+    framerate: Option<f64>,
+) -> Result<(Streaming, Vec<Request>), (u8, String)> {
+    cam.configure(cfgs).map_err(|e| (8, e.to_string()))?;
+
+    let mut streams = Vec::new();
+    let mut sizes = Vec::new();
+    for i in 0..cfgs.len() {
+        let cfg = cfgs.get(i).unwrap();
+        let size = cfg.get_size();
+        streams.push(cfg.stream().unwrap());
+        sizes.push((size.width, size.height));
+    }
+
+    // Allocate frame buffers for each stream
+    let mut alloc = FrameBufferAllocator::new(cam);
+    let mut buffers = Vec::new();
+    for stream in streams.iter() {
+        buffers.push(alloc.alloc(stream).map_err(|e| (8, e.to_string()))?.into_iter());
+    }
+
+    // Every request fills a buffer for every stream, so there are only as many requests as
+    // the stream with the fewest buffers has.
+    let count = buffers.iter().map(|b| b.len()).min().unwrap_or(0);
+    let reqs = (0..count).map(|_| {
+        let mut req = cam.create_request(None).unwrap();
+        for (stream, bufs) in streams.iter().zip(buffers.iter_mut()) {
+            let buf = MemoryMappedFrameBuffer::new(bufs.next().unwrap()).unwrap();
+            req.add_buffer(stream, buf).unwrap();
+        }
+        req
+    }).collect::<Vec<_>>();
+
+    // The camera can go slower than this if exposures take longer, but never faster.
+    let mut start_controls = ControlList::new();
+    if let Some(framerate) = framerate {
+        let min_duration = (1_000_000.0 / framerate).round() as i64;
+        start_controls.set(controls::FrameDurationLimits([min_duration, i64::MAX]))
+            .map_err(|e| (8, format!("couldn't set the framerate: {}", e)))?;
+    }
+    cam.start(Some(&start_controls)).map_err(|e| (8, e.to_string()))?;
+
+    Ok((Streaming {
+        streams,
+        sizes,
+        _alloc: alloc,
+    }, reqs))
+}
+
+// Fields drop in order, and the requests have to go before their buffers, which have to go
+// before the camera.
+struct LibcameraCapture<'d> {
+    rx: Receiver<Request>,
+    unused_reqs: Vec<Request>,
+    // None if reconfiguring failed halfway, and the camera is stopped for good
+    streaming: Option<Streaming>,
+    // in microseconds, from the last frame that came in
+    frame_duration: Option<i64>,
+    // Every control set so far, to set again after a reconfigure
+    applied: Vec<(String, ControlValue)>,
+    // Controls go out with the next request queued, and libcamera keeps them from there.
+    pending: Vec<(String, ControlValue)>,
+    config: CaptureConfig,
+    cam: ActiveCamera<'d>,
+}
+
+impl LibcameraCapture<'_> {
+    fn queue(&mut self, mut req: Request) -> VisResult<()> {
+        for (name, value) in self.pending.drain(..) {
+            // already checked in set_control
+            let _ = apply_control(req.controls_mut(), &name, value);
+        }
+        unwrap_or_fail!(8, self.cam.queue_request(req));
+        Ok(())
+    }
+
+    fn resume(&mut self, (streaming, reqs): (Streaming, Vec<Request>)) {
+        self.streaming = Some(streaming);
+        self.frame_duration = None;
+        self.unused_reqs = reqs;
+        // libcamera doesn't promise to keep controls over a restart
+        self.pending = self.applied.clone();
+    }
+}
+
+impl Capture for LibcameraCapture<'_> {
+    fn channels(&self) -> usize {
+        self.config.streams.len()
+    }
+
+    fn format(&self, channel: usize) -> FourCC {
+        self.config.streams[channel].format
+    }
+
+    fn size(&self, channel: usize) -> (u32, u32) {
+        self.streaming.as_ref().map_or((0, 0), |s| s.sizes[channel])
+    }
+
+    fn framerate(&self) -> Option<f64> {
+        self.frame_duration.filter(|&d| d > 0).map(|d| 1_000_000.0 / d as f64)
+    }
+
+    fn next_frame(&mut self, timeout: Duration, wanted: &[bool]) -> VisResult<Option<Vec<Option<Vec<u8>>>>> {
+        let Some(streams) = self.streaming.as_ref().map(|s| s.streams.clone()) else {
+            fail!(8, "camera was left stopped by a failed reconfigure");
+        };
+        while let Some(req) = self.unused_reqs.pop() {
+            self.queue(req)?;
+        }
+
+        let mut req = match self.rx.recv_timeout(timeout) {
+            Ok(req) => req,
+            Err(RecvTimeoutError::Timeout) => return Ok(None),
+            Err(RecvTimeoutError::Disconnected) => {
+                fail!(9, "camera disconnected");
+            }
+        };
+        if let Ok(duration) = req.metadata().get::<controls::FrameDuration>() {
+            self.frame_duration = Some(*duration);
+        }
+
+        let data = streams.iter().zip(wanted).map(|(stream, &wanted)| {
+            if !wanted {
+                return None;
+            }
+            let frame_buffer: &MemoryMappedFrameBuffer<FrameBuffer> = req.buffer(stream).unwrap();
+            let planes = frame_buffer.data();
+            let frame_data = planes.first().unwrap();
+            let bytes_used = frame_buffer.metadata().unwrap().planes().get(0).unwrap().bytes_used as usize;
+            Some(frame_data[..bytes_used].to_vec())
+        }).collect();
+
+        req.reuse(ReuseFlag::REUSE_BUFFERS);
+        self.queue(req)?;
+        Ok(Some(data))
+    }
+
+    fn idle(&mut self) -> VisResult<()> {
+        match self.rx.try_recv() {
+            Ok(mut req) => {
+                if let Ok(duration) = req.metadata().get::<controls::FrameDuration>() {
+                    self.frame_duration = Some(*duration);
+                }
+                // discard frame data
+                req.reuse(ReuseFlag::REUSE_BUFFERS);
+                self.unused_reqs.push(req);
+            }
+            Err(TryRecvError::Empty) => {/* do nothing */}
+            Err(TryRecvError::Disconnected) => {
+                fail!(9, "camera disconnected");
+            }
+        }
+        Ok(())
+    }
+
+    fn controls(&self) -> Vec<ControlInfo> {
+        // libcamera-rs doesn't give a way into the camera's ControlInfoMap, so this can't
+        // say which of these the camera actually takes, or their ranges.
+        CONTROLS.iter().map(|name| ControlInfo {
+            name: name.to_string(),
+            min: None,
+            max: None,
+            default: None,
+        }).collect()
+    }
+
+    fn set_control(&mut self, name: &str, value: ControlValue) -> Result<(), String> {
+        check_control(&self.controls(), name, value)?;
+        // Trying it on a throwaway list catches type mismatches now, rather than when the
+        // next request is queued, where there's no one to tell.
+        apply_control(&mut ControlList::new(), name, value)?;
+        for list in [&mut self.pending, &mut self.applied] {
+            list.retain(|(n, _)| n != name);
+            list.push((name.to_string(), value));
+        }
+        Ok(())
+    }
+
+    fn reconfigure(&mut self, config: &CaptureConfig) -> Result<(), String> {
+        // Anything wrong with the new config shows up here, while the old one's still running.
+        let mut cfgs = generate_config(&self.cam, config).map_err(|(_, e)| e)?;
+
+        self.cam.stop().map_err(|e| e.to_string())?;
+        // Stopping hands back every request still in flight. Dropping them all, then the
+        // allocator, frees the old buffers.
+        while self.rx.try_recv().is_ok() {}
+        self.unused_reqs.clear();
+        self.streaming = None;
+
+        match start_streaming(&mut self.cam, &mut cfgs, config.framerate) {
+            Ok(started) => {
+                self.config = config.clone();
+                self.resume(started);
+                Ok(())
+            }
+            Err((_, e)) => {
+                // Go back to what was working, if it still does.
+                let restarted = generate_config(&self.cam, &self.config)
+                    .and_then(|mut cfgs| start_streaming(&mut self.cam, &mut cfgs, self.config.framerate));
+                if let Ok(started) = restarted {
+                    self.resume(started);
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Every control that can be set by name.
+const CONTROLS: &[&str] = &[
+    "AeEnable",
+    "ExposureTime",
+    "ExposureValue",
+    "AnalogueGain",
+    "AwbEnable",
+    "AfMode",
+    "LensPosition",
+    "Brightness",
+    "Contrast",
+    "Saturation",
+    "Sharpness",
+];
+
+fn apply_control(list: &mut ControlList, name: &str, value: ControlValue) -> Result<(), String> {
+    fn set<C: Control>(list: &mut ControlList, name: &str, value: LcControlValue) -> Result<(), String> {
+        let control = C::try_from(value).map_err(|e| format!("bad value for {}: {}", name, e))?;
+        list.set(control).map_err(|e| e.to_string())
+    }
+
+    // Every integer control on the list is an i32 on libcamera's end.
+    let value = match value {
+        ControlValue::Bool(v) => LcControlValue::from(v),
+        ControlValue::Int(v) => match i32::try_from(v) {
+            Ok(v) => LcControlValue::from(v),
+            Err(_) => return Err(format!("{} is out of range for {}", v, name)),
+        },
+        ControlValue::Float(v) => LcControlValue::from(v),
+    };
+    match name {
+        "AeEnable" => set::<controls::AeEnable>(list, name, value),
+        "ExposureTime" => set::<controls::ExposureTime>(list, name, value),
+        "ExposureValue" => set::<controls::ExposureValue>(list, name, value),
+        "AnalogueGain" => set::<controls::AnalogueGain>(list, name, value),
+        "AwbEnable" => set::<controls::AwbEnable>(list, name, value),
+        "AfMode" => set::<controls::AfMode>(list, name, value),
+        "LensPosition" => set::<controls::LensPosition>(list, name, value),
+        "Brightness" => set::<controls::Brightness>(list, name, value),
+        "Contrast" => set::<controls::Contrast>(list, name, value),
+        "Saturation" => set::<controls::Saturation>(list, name, value),
+        "Sharpness" => set::<controls::Sharpness>(list, name, value),
+        _ => Err(format!("camera has no control named {}", name)),
+    }
+}
+
+fn translate_role(role: crate::parser::StreamRole) -> StreamRole {
+    match role {
+        crate::parser::StreamRole::Video => StreamRole::VideoRecording,
+        crate::parser::StreamRole::Viewfinder => StreamRole::ViewFinder,
+        crate::parser::StreamRole::Still => StreamRole::StillCapture,
+        crate::parser::StreamRole::Raw => StreamRole::Raw,
+    }
+}
+
+fn translate_pixel_format(fourcc: FourCC) -> pf::PixelFormat {
+    let format: PixelFormat = fourcc.to_string().parse().unwrap();
+    pf::PixelFormat::new(u32::from_le_bytes(format.fourcc()), format.modifier())
+}
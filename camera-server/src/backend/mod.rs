@@ -0,0 +1,175 @@
+//! Where frames come from. The server only ever talks to a `Backend`, so it can be run against
+//! something other than a real camera.
+
+mod fake;
+#[cfg(feature = "libcamera")]
+mod libcam;
+
+use crate::parser::{BackendKind, FakeArgs, FourCC, Launch, StreamRole};
+use crate::shared::*;
+use vistream_protocol::camera::{ControlInfo, ControlValue};
+
+use std::time::Duration;
+
+pub struct CameraInfo {
+    /// The camera's model, which isn't necessarily unique
+    pub name: String,
+    pub id: String,
+}
+
+#[derive(Clone)]
+pub struct StreamConfig {
+    pub role: StreamRole,
+    pub format: FourCC,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+#[derive(Clone)]
+pub struct CaptureConfig {
+    /// One per channel, starting with the main stream.
+    pub streams: Vec<StreamConfig>,
+    pub buffer_count: u32,
+    /// The most frames per second to capture, for every stream at once.
+    pub framerate: Option<f64>,
+}
+
+impl From<&Launch> for CaptureConfig {
+    fn from(launch: &Launch) -> CaptureConfig {
+        let main = StreamConfig {
+            role: launch.role,
+            format: launch.format,
+            width: launch.width,
+            height: launch.height,
+        };
+        let extra = launch.channels.iter().map(|channel| StreamConfig {
+            role: channel.role,
+            format: channel.format.unwrap_or(launch.format),
+            width: Some(channel.width),
+            height: Some(channel.height),
+        });
+        CaptureConfig {
+            streams: std::iter::once(main).chain(extra).collect(),
+            buffer_count: launch.buffer_count,
+            framerate: launch.framerate,
+        }
+    }
+}
+
+/// A configured, running camera, with a channel for each of its streams.
+pub trait Capture {
+    fn channels(&self) -> usize;
+    fn format(&self, channel: usize) -> FourCC;
+    fn size(&self, channel: usize) -> (u32, u32);
+    /// The rate frames are actually coming in at, which can be less than asked for. `None`
+    /// if the camera hasn't said.
+    fn framerate(&self) -> Option<f64>;
+    /// Waits up to `timeout` for the next frame. `None` if there wasn't one in time.
+    /// Otherwise, there's data for every channel `wanted` is true for, and `None` for the
+    /// rest, so nobody pays to copy frames no one's going to see.
+    fn next_frame(&mut self, timeout: Duration, wanted: &[bool]) -> VisResult<Option<Vec<Option<Vec<u8>>>>>;
+    /// Called instead of `next_frame` while nobody wants frames, to throw away whatever
+    /// comes in.
+    fn idle(&mut self) -> VisResult<()>;
+    /// The controls this camera takes.
+    fn controls(&self) -> Vec<ControlInfo>;
+    /// Applies a control to frames from here on. Errors go back to the client that asked,
+    /// so they're just a message instead of an exit code.
+    fn set_control(&mut self, name: &str, value: ControlValue) -> Result<(), String>;
+    /// Stops the camera, configures its streams with new formats and/or sizes, and starts it
+    /// again.
+    /// If the new config can't be used, the old one should be left running. Otherwise,
+    /// failing leaves the camera stopped, so the next `next_frame` fails too.
+    fn reconfigure(&mut self, config: &CaptureConfig) -> Result<(), String>;
+}
+
+pub trait Backend {
+    fn cameras(&self) -> VisResult<Vec<CameraInfo>>;
+
+    /// Acquires and configures the camera with the given id, and hands it to `serve` for as
+    /// long as it runs. The camera is released once this returns.
+    ///
+    /// This is inside-out from just returning the `Capture` so that backends can keep
+    /// everything borrowed from the camera on the stack (looking at you, libcamera-rs).
+    fn capture(
+        &self,
+        id: &str,
+        config: &CaptureConfig,
+        serve: &mut dyn FnMut(&mut dyn Capture) -> VisResult<()>,
+    ) -> VisResult<()>;
+}
+
+pub fn from_args(kind: BackendKind, fake: &FakeArgs) -> VisResult<Box<dyn Backend>> {
+    match kind {
+        #[cfg(feature = "libcamera")]
+        BackendKind::Libcamera => Ok(Box::new(libcam::Libcamera)),
+        #[cfg(not(feature = "libcamera"))]
+        BackendKind::Libcamera => {
+            fail!(4, "built without libcamera support, try --backend fake");
+        }
+        BackendKind::Fake => Ok(Box::new(fake::Fake::new(fake))),
+    }
+}
+
+/// Ids of every camera going by the given name or id.
+pub fn matching_cameras(backend: &dyn Backend, name: &str) -> VisResult<Vec<String>> {
+    Ok(backend.cameras()?
+        .into_iter()
+        .filter(|cam| cam.name == name || cam.id == name)
+        .map(|cam| cam.id)
+        .collect())
+}
+
+/// Picks whichever of `sizes` best matches the requested width and/or height, or the biggest
+/// if neither was asked for.
+pub fn closest_size(sizes: Vec<(u32, u32)>, width: Option<u32>, height: Option<u32>) -> Option<(u32, u32)> {
+    match (width, height) {
+        (None, None) => sizes.into_iter().max_by_key(|s| s.0 * s.1),
+        (None, Some(height)) => {
+            // sort solely based on height
+            let true_height = sizes.iter().min_by_key(|s| s.1.abs_diff(height))?.1;
+            sizes.into_iter().filter(|s| s.1 == true_height).max_by_key(|s| s.0)
+        }
+        (Some(width), None) => {
+            // sort solely based on width
+            let true_width = sizes.iter().min_by_key(|s| s.0.abs_diff(width))?.0;
+            sizes.into_iter().filter(|s| s.0 == true_width).max_by_key(|s| s.1)
+        }
+        (Some(width), Some(height)) => {
+            // sort based on lowest dw and dh
+            let true_width = sizes.iter().min_by_key(|s| s.0.abs_diff(width))?.0;
+            sizes.into_iter().filter(|s| s.0 == true_width).min_by_key(|s| s.1.abs_diff(height))
+        }
+    }
+}
+
+/// Checks a control against what the camera says it takes: it has to exist, be the right
+/// type, and be in range, as far as the range is known.
+pub fn check_control(controls: &[ControlInfo], name: &str, value: ControlValue) -> Result<(), String> {
+    let Some(info) = controls.iter().find(|c| c.name == name) else {
+        return Err(format!("camera has no control named {}", name));
+    };
+    if let Some(example) = info.default.or(info.min).or(info.max) {
+        if std::mem::discriminant(&example) != std::mem::discriminant(&value) {
+            return Err(format!("{} takes a value like {}, not {}", name, example, value));
+        }
+    }
+    // as f64 is exact for everything a camera would actually use
+    let as_f64 = |v: ControlValue| match v {
+        ControlValue::Int(v) => Some(v as f64),
+        ControlValue::Float(v) => Some(v as f64),
+        ControlValue::Bool(_) => None,
+    };
+    let v = as_f64(value);
+    if let (Some(v), Some(min)) = (v, info.min.and_then(as_f64)) {
+        if v < min {
+            return Err(format!("{} is below the minimum for {} ({})", value, name, info.min.unwrap()));
+        }
+    }
+    if let (Some(v), Some(max)) = (v, info.max.and_then(as_f64)) {
+        if v > max {
+            return Err(format!("{} is above the maximum for {} ({})", value, name, info.max.unwrap()));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,126 @@
+//! Converting frames from whatever a camera produces into whatever clients asked for, so
+//! clients that want different formats can share a camera.
+//!
+//! Everything goes through packed RGB on the way. Bayer formats would need demosaicing, so
+//! they're passed through as-is, never converted.
+
+use vistream_protocol::camera::PixelFormat;
+
+const JPEG_QUALITY: u8 = 85;
+
+/// Formats frames can be converted into.
+pub const TARGETS: [PixelFormat; 7] = [
+    PixelFormat::RGB,
+    PixelFormat::BGR,
+    PixelFormat::RGBA,
+    PixelFormat::BGRA,
+    PixelFormat::YUYV,
+    PixelFormat::MJPEG,
+    PixelFormat::Luma,
+];
+
+pub fn can_convert(from: PixelFormat, to: PixelFormat) -> bool {
+    from == to || (!from.is_bayer() && from != PixelFormat::Luma && TARGETS.contains(&to))
+}
+
+pub fn convert(data: &[u8], from: PixelFormat, to: PixelFormat, width: u32, height: u32) -> Result<Vec<u8>, String> {
+    if from == to {
+        return Ok(data.to_vec());
+    }
+    if !can_convert(from, to) {
+        return Err(format!("can't convert {:?} to {:?}", from, to));
+    }
+    let rgb = to_rgb(data, from, width as usize, height as usize)?;
+    from_rgb(rgb, to, width as usize, height as usize)
+}
+
+fn to_rgb(data: &[u8], from: PixelFormat, width: usize, height: usize) -> Result<Vec<u8>, String> {
+    let pixels = width * height;
+    let expected = match from {
+        PixelFormat::RGB | PixelFormat::BGR => pixels * 3,
+        PixelFormat::RGBA | PixelFormat::BGRA => pixels * 4,
+        PixelFormat::YUYV => pixels * 2,
+        // compressed, so it's whatever it is
+        _ => data.len(),
+    };
+    if data.len() < expected {
+        return Err(format!("{:?} frame is {} bytes, expected {}", from, data.len(), expected));
+    }
+    // buffers can have padding at the end
+    let data = &data[..expected];
+
+    Ok(match from {
+        PixelFormat::RGB => data.to_vec(),
+        PixelFormat::BGR => data.chunks_exact(3).flat_map(|p| [p[2], p[1], p[0]]).collect(),
+        PixelFormat::RGBA => data.chunks_exact(4).flat_map(|p| [p[0], p[1], p[2]]).collect(),
+        PixelFormat::BGRA => data.chunks_exact(4).flat_map(|p| [p[2], p[1], p[0]]).collect(),
+        PixelFormat::YUYV => data.chunks_exact(4).flat_map(|p| {
+            let [r0, g0, b0] = yuv_to_rgb(p[0], p[1], p[3]);
+            let [r1, g1, b1] = yuv_to_rgb(p[2], p[1], p[3]);
+            [r0, g0, b0, r1, g1, b1]
+        }).collect(),
+        PixelFormat::MJPEG => {
+            let mut decoder = jpeg_decoder::Decoder::new(data);
+            let decoded = decoder.decode().map_err(|e| format!("bad jpeg from camera: {}", e))?;
+            let info = decoder.info().unwrap();
+            if info.width as usize != width || info.height as usize != height {
+                return Err(format!("jpeg from camera is {}x{}, expected {}x{}", info.width, info.height, width, height));
+            }
+            match info.pixel_format {
+                jpeg_decoder::PixelFormat::RGB24 => decoded,
+                jpeg_decoder::PixelFormat::L8 => decoded.into_iter().flat_map(|v| [v; 3]).collect(),
+                other => return Err(format!("can't use {:?} jpegs", other)),
+            }
+        }
+        _ => return Err(format!("can't convert from {:?}", from)),
+    })
+}
+
+fn from_rgb(rgb: Vec<u8>, to: PixelFormat, width: usize, height: usize) -> Result<Vec<u8>, String> {
+    Ok(match to {
+        PixelFormat::RGB => rgb,
+        PixelFormat::BGR => rgb.chunks_exact(3).flat_map(|p| [p[2], p[1], p[0]]).collect(),
+        PixelFormat::RGBA => rgb.chunks_exact(3).flat_map(|p| [p[0], p[1], p[2], 255]).collect(),
+        PixelFormat::BGRA => rgb.chunks_exact(3).flat_map(|p| [p[2], p[1], p[0], 255]).collect(),
+        PixelFormat::Luma => rgb.chunks_exact(3).map(|p| rgb_to_yuv(p[0], p[1], p[2])[0]).collect(),
+        PixelFormat::YUYV => rgb.chunks_exact(6).flat_map(|p| {
+            let [y0, u0, v0] = rgb_to_yuv(p[0], p[1], p[2]);
+            let [y1, u1, v1] = rgb_to_yuv(p[3], p[4], p[5]);
+            [y0, ((u0 as u16 + u1 as u16) / 2) as u8, y1, ((v0 as u16 + v1 as u16) / 2) as u8]
+        }).collect(),
+        PixelFormat::MJPEG => {
+            let (Ok(w), Ok(h)) = (u16::try_from(width), u16::try_from(height)) else {
+                return Err(format!("{}x{} is too big for a jpeg", width, height));
+            };
+            let mut buf = Vec::new();
+            jpeg_encoder::Encoder::new(&mut buf, JPEG_QUALITY)
+                .encode(&rgb, w, h, jpeg_encoder::ColorType::Rgb)
+                .map_err(|e| format!("couldn't encode jpeg: {}", e))?;
+            buf
+        }
+        _ => return Err(format!("can't convert to {:?}", to)),
+    })
+}
+
+// Full-range BT.601, which is what JPEG uses too.
+fn yuv_to_rgb(y: u8, u: u8, v: u8) -> [u8; 3] {
+    let y = y as f32;
+    let u = u as f32 - 128.0;
+    let v = v as f32 - 128.0;
+    let clamp = |x: f32| x.round().clamp(0.0, 255.0) as u8;
+    [
+        clamp(y + 1.402 * v),
+        clamp(y - 0.344136 * u - 0.714136 * v),
+        clamp(y + 1.772 * u),
+    ]
+}
+
+fn rgb_to_yuv(r: u8, g: u8, b: u8) -> [u8; 3] {
+    let (r, g, b) = (r as f32, g as f32, b as f32);
+    let clamp = |x: f32| x.round().clamp(0.0, 255.0) as u8;
+    [
+        clamp(0.299 * r + 0.587 * g + 0.114 * b),
+        clamp(128.0 - 0.168736 * r - 0.331264 * g + 0.5 * b),
+        clamp(128.0 + 0.5 * r - 0.418688 * g - 0.081312 * b),
+    ]
+}
@@ -1,7 +1,5 @@
-use libcamera::{
-    camera_manager::CameraManager,
-    properties,
-};
+mod backend;
+mod convert;
 mod parser;
 mod server;
 mod shared;
@@ -30,24 +28,26 @@ fn main()-> std::process::ExitCode {
 fn pseudo_main() -> VisResult<()> {
     std::env::set_var("LIBCAMERA_LOG_LEVELS", "*:4");
     let args = parser::Cli::parse();
+    let backend = backend::from_args(args.backend, &args.fake)?;
+    let backend = backend.as_ref();
 
     match args.command {
         parser::Command::List(list) => {
             // list cameras as "model (id)" (possibly with libcamera index?)
             if list.piped {
                 let mut write = BufWriter::new(stdout());
-                let cams = get_camera_names()?.cameras;
+                let cams = get_camera_names(backend)?.cameras;
                 unwrap_or_fail!(11, cams.serialize(&mut Serializer::new(&mut write)));
                 unwrap_or_fail!(11, write.flush());
             } else {
-                for cam in get_camera_names()?.cameras {
+                for cam in get_camera_names(backend)?.cameras {
                     println!("{}", cam);
                 }
             }
         }
         parser::Command::Launch(launch) => {
             // This is where we do the actual server stuff
-            server::launch(launch)?;
+            server::launch(launch, backend)?;
         }
         parser::Command::Alias(alias) => {
             // TODO figure out what it takes to list and rm aliases
@@ -67,12 +67,12 @@ fn pseudo_main() -> VisResult<()> {
             }
         }
         parser::Command::Resolve(resolve) => {
-            let cam_id = full_resolve_name(resolve.name)?;
+            let cam_id = full_resolve_name(backend, resolve.name)?;
             println!("{}", cam_id);
         }
 
         parser::Command::Check(check) => {
-            let name = full_resolve_name(check.name)?;
+            let name = full_resolve_name(backend, check.name)?;
             if is_camera_used(&name)? {
                 if check.quiet {
                     fail!(255, "");
@@ -87,7 +87,7 @@ fn pseudo_main() -> VisResult<()> {
         }
 
         parser::Command::Stop(stop) => {
-            let name = full_resolve_name(stop.name)?;
+            let name = full_resolve_name(backend, stop.name)?;
             let pids = get_camera_pids()?;
             let Some(pid) = pids.get(&name)  else {
                 fail!(20, "Camera not found (is it running?)");
@@ -102,22 +102,14 @@ fn pseudo_main() -> VisResult<()> {
     Ok(())
 }
 
-fn get_camera_names() -> VisResult<CameraList> {
-    let mgr = CameraManager::new().unwrap();
-    let cameras = mgr.cameras();
-
+fn get_camera_names(backend: &dyn backend::Backend) -> VisResult<CameraList> {
     let used_cameras = get_used_cameras()?;
 
-    let mut names = vec![];
-    for i in 0..cameras.len() {
-        let cam = cameras.get(i).unwrap();
-        let listing = CameraListing {
-            name: cam.properties().get::<properties::Model>().unwrap().to_string(),
-            id: cam.id().to_string(),
-            acquired: used_cameras.contains(&cam.id().to_string()),
-        };
-        names.push(listing);
-    }
+    let names = backend.cameras()?.into_iter().map(|cam| CameraListing {
+        acquired: used_cameras.contains(&cam.id),
+        name: cam.name,
+        id: cam.id,
+    }).collect();
 
     Ok(CameraList{cameras: names})
 }
@@ -147,26 +139,11 @@ pub fn remove_alias(alias: parser::RemoveAlias) -> VisResult<()> {
     save_aliases(aliases)
 }
 
-pub fn full_resolve_name(src_name: String) -> VisResult<String> {
+pub fn full_resolve_name(backend: &dyn backend::Backend, src_name: String) -> VisResult<String> {
     let name = resolve_alias(&src_name)?;
-    let mgr = CameraManager::new().unwrap();
-    let cameras = mgr.cameras();
-
-    let mut cam_id = None;
-
-    for i in 0..cameras.len() {
-        let cam = cameras.get(i).unwrap();
-        let cam_name = cam.properties().get::<properties::Model>().unwrap().to_string();
-        if cam_name == name || cam.id() == name {
-            if cam_id.is_some() {
-                fail!(12, "\"{}\" is not unambiguous", name);
-            }
-            cam_id = Some(cam.id().to_string());
-        }
+    match backend::matching_cameras(backend, &name)?.as_slice() {
+        [cam_id] => Ok(cam_id.clone()),
+        [] => {fail!(12, "\"{}\" is not recognized by vistream", src_name);}
+        _ => {fail!(12, "\"{}\" is not unambiguous", name);}
     }
-    if cam_id.is_none() {
-        fail!(12, "\"{}\" is not recognized by vistream", src_name);
-    }
-
-    Ok(cam_id.unwrap())
 }
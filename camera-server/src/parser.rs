@@ -1,10 +1,70 @@
 use clap::{Parser, Subcommand, Args, ValueEnum};
+use vistream_protocol::camera::PixelFormat;
 
 #[derive(Parser)]
 #[command(version, about, long_about=None)]
 pub(crate) struct Cli {
     #[command(subcommand)]
     pub command: Command,
+
+    /// Where frames come from
+    #[arg(long, global = true, value_enum, default_value_t = BackendKind::Libcamera, env = "VISTREAM_BACKEND")]
+    pub backend: BackendKind,
+
+    #[command(flatten)]
+    pub fake: FakeArgs,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackendKind {
+    /// Real cameras, through libcamera
+    Libcamera,
+    /// Synthetic frames, for testing without a camera
+    Fake,
+}
+
+/// Settings for `--backend fake`. Ignored otherwise.
+#[derive(Args)]
+pub struct FakeArgs {
+    /// Number of fake cameras, with ids fake/0, fake/1, ...
+    #[arg(long, global = true, value_name = "COUNT", default_value_t = 1, env = "VISTREAM_FAKE_CAMERAS")]
+    pub fake_cameras: usize,
+
+    /// Formats the fake cameras support (no MJPG)
+    #[arg(long, global = true, value_enum, value_name = "FOURCC", value_delimiter = ',',
+          default_value = "RG24,BG24,RA24,BA24,YUYV,RGGB,RG10,RG12,PRAA,PRCC", env = "VISTREAM_FAKE_FORMATS")]
+    pub fake_formats: Vec<FourCC>,
+
+    /// Frame sizes the fake cameras support, as WIDTHxHEIGHT
+    #[arg(long, global = true, value_name = "SIZE", value_delimiter = ',', value_parser = parse_size,
+          default_value = "640x480,1280x720,1920x1080", env = "VISTREAM_FAKE_SIZES")]
+    pub fake_sizes: Vec<(u32, u32)>,
+
+    #[arg(long, global = true, value_name = "FPS", default_value_t = 30.0, env = "VISTREAM_FAKE_FRAMERATE")]
+    pub fake_framerate: f64,
+
+    /// Fail to acquire the camera, as if something else has it
+    #[arg(long, global = true, env = "VISTREAM_FAKE_FAIL_OPEN")]
+    pub fake_fail_open: bool,
+
+    /// Act as if the camera was unplugged after this many frames
+    #[arg(long, global = true, value_name = "FRAMES", env = "VISTREAM_FAKE_FAIL_AFTER")]
+    pub fake_fail_after: Option<usize>,
+}
+
+fn parse_size(s: &str) -> Result<(u32, u32), String> {
+    let (width, height) = s.split_once(['x', 'X']).ok_or_else(|| format!("expected WIDTHxHEIGHT, got \"{}\"", s))?;
+    let width = width.trim().parse().map_err(|e| format!("bad width: {}", e))?;
+    let height = height.trim().parse().map_err(|e| format!("bad height: {}", e))?;
+    Ok((width, height))
+}
+
+fn parse_framerate(s: &str) -> Result<f64, String> {
+    let framerate: f64 = s.trim().parse().map_err(|e| format!("bad framerate: {}", e))?;
+    if !(framerate.is_finite() && framerate > 0.0) {
+        return Err(format!("framerate has to be more than 0, got {}", s));
+    }
+    Ok(framerate)
 }
 
 #[derive(Subcommand)]
@@ -38,13 +98,76 @@ pub enum FourCC {
     YUYV,
     #[value(alias = "MJPG")]
     MJPG,
+    /// 8-bit Bayer RGGB
+    #[value(alias = "RGGB")]
+    RGGB,
+    /// 10-bit Bayer RGGB, one sample per 16-bit word
+    #[value(alias = "RG10")]
+    RG10,
+    /// 12-bit Bayer RGGB, one sample per 16-bit word
+    #[value(alias = "RG12")]
+    RG12,
+    /// 10-bit Bayer RGGB, CSI-2 packed
+    #[value(alias = "pRAA", alias = "PRAA")]
+    PRAA,
+    /// 12-bit Bayer RGGB, CSI-2 packed
+    #[value(alias = "pRCC", alias = "PRCC")]
+    PRCC,
+}
+impl FourCC {
+    /// The FourCC for a protocol pixel format, if it's one cameras can produce.
+    pub fn from_pixel_format(format: PixelFormat) -> Option<FourCC> {
+        <FourCC as ValueEnum>::from_str(&format.to_string(), true).ok()
+    }
 }
+
 impl std::fmt::Display for FourCC {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "{:?}", self)
     }
 }
 
+/// What a stream is for, which cameras can use to pick sensible defaults for it.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamRole {
+    Video,
+    Viewfinder,
+    Still,
+    Raw,
+}
+
+#[derive(Clone, Debug)]
+pub struct ChannelSpec {
+    pub role: StreamRole,
+    pub width: u32,
+    pub height: u32,
+    pub format: Option<FourCC>,
+}
+
+fn parse_channel(s: &str) -> Result<ChannelSpec, String> {
+    let mut parts = s.split(':').collect::<Vec<_>>();
+    let role = match parts.first() {
+        Some(part) if !part.starts_with(|c: char| c.is_ascii_digit()) => {
+            let role = <StreamRole as ValueEnum>::from_str(part, true)?;
+            parts.remove(0);
+            role
+        }
+        _ => StreamRole::Viewfinder,
+    };
+    let (size, format) = match parts.as_slice() {
+        [size] => (size, None),
+        [size, format] => (size, Some(<FourCC as ValueEnum>::from_str(format, true)?)),
+        _ => return Err(format!("expected [ROLE:]WIDTHxHEIGHT[:FOURCC], got \"{}\"", s)),
+    };
+    let (width, height) = parse_size(size)?;
+    Ok(ChannelSpec {
+        role,
+        width,
+        height,
+        format,
+    })
+}
+
 #[derive(Args)]
 pub struct List {
     #[arg(long)]
@@ -62,11 +185,21 @@ pub struct Launch {
     #[arg(long, value_enum, default_value_t = FourCC::RG24, value_name = "FOURCC")]
     pub format: FourCC,
 
+    /// What the camera should tune the main stream for
+    #[arg(long, value_enum, default_value_t = StreamRole::Video)]
+    pub role: StreamRole,
+
+    /// Another stream from the same camera, as [ROLE:]WIDTHxHEIGHT[:FOURCC] (e.g.
+    /// viewfinder:640x480). Channels are numbered from 1, in order, with the main stream as 0.
+    /// The format defaults to --format, and the role to viewfinder.
+    #[arg(long = "channel", value_name = "SPEC", value_parser = parse_channel)]
+    pub channels: Vec<ChannelSpec>,
+
     #[arg(long, alias = "buffers", value_name = "COUNT", default_value_t = 1)]
     pub buffer_count: u32,
-    // /// Requested maximum framerate. Default: unbounded
-    // #[arg(long, alias = "fps", value_name = "FPS")]
-    // pub framerate: Option<f64>,
+    /// Requested maximum framerate. Default: unbounded
+    #[arg(long, alias = "fps", value_name = "FPS", value_parser = parse_framerate)]
+    pub framerate: Option<f64>,
     /// Requested width of the output, in pixels. (not guaranteed to be respected)
     #[arg(long)]
     pub width: Option<u32>,
@@ -1,11 +1,12 @@
-use crate::parser::{Launch, FourCC};
-use vistream_protocol::camera::{PixelFormat, Frame, ClientMessage, Status};
+use crate::parser::{FourCC, Launch};
+use crate::backend::{self, Backend, Capture, CaptureConfig};
+use crate::convert;
+use vistream_protocol::camera::{Frame, Command, Message, MessageReader, Status, PixelFormat};
+use vistream_protocol::stream::Encoding;
+use vistream_protocol::hello::{HandshakeError, Hello, HelloReader};
 use vistream_protocol::fs::*;
 use crate::shared::*;
 
-use serde::{Serialize};
-use rmp_serde::{Serializer};
-
 
 macro_rules! unwrap_or_fail_with_free {
     ($code: expr, $name: expr, $value: expr) => {
@@ -19,74 +20,68 @@ macro_rules! unwrap_or_fail_with_free {
     }
 }
 
-macro_rules! fail_with_free {
-    ($code:expr, $name:expr, $e:expr) => {
-        let _ = free_camera($name);
-        fail!($code, $e)
-    };
-    ($code:expr, $name:expr, $($tts:tt)*) => {
-        let _ = free_camera($name);
-        fail!($code, $($tts)*);
-    };
-}
-
-
-use libcamera::{
-    camera::{
-        CameraConfigurationStatus,
-        Camera,
-    },
-    camera_manager::{CameraManager, CameraList},
-    framebuffer::AsFrameBuffer,
-    framebuffer_allocator::{FrameBuffer, FrameBufferAllocator},
-    framebuffer_map::MemoryMappedFrameBuffer,
-    pixel_format as pf,
-    properties,
-    stream::{
-        StreamRole,
-        StreamConfigurationRef,
-    },
-    geometry::Size,
-    request::ReuseFlag,
-};
-
 use std::os::unix::net::{SocketAddr, UnixListener, UnixStream};
 use std::os::linux::net::{SocketAddrExt};
 
-use std::sync::mpsc::{self, TryRecvError, RecvTimeoutError};
-
 use std::fs::{File};
-use std::io::{Write, Read};
+use std::io::{Write};
+use std::time::{Duration, Instant};
 
+// Clients get this long to say hello before they're dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1);
 
-struct CamIter<'a> {
-    list: &'a CameraList<'a>,
-    index: usize,
+/// A client that's been sent our hello, and hasn't finished sending theirs.
+struct Greeting {
+    socket: UnixStream,
+    addr: SocketAddr,
+    reader: HelloReader,
+    since: Instant,
 }
 
-impl<'a> CamIter<'a> {
-    fn new(cameras: &'a CameraList<'a>) -> CamIter<'a> {
-        CamIter {
-            list: cameras,
-            index: 0,
+impl Greeting {
+    fn start(mut conn: (UnixStream, SocketAddr), hello: &Hello) -> Option<Greeting> {
+        // Their side comes in a bit at a time from the serving loop, so a client that never
+        // says anything can't hold up everyone else's frames.
+        conn.0.set_nonblocking(true).ok()?;
+        if let Err(e) = hello.write_to(&mut conn.0) {
+            eprintln!("dropping connection: {}", e);
+            return None;
         }
+        Some(Greeting {
+            socket: conn.0,
+            addr: conn.1,
+            reader: HelloReader::new(),
+            since: Instant::now(),
+        })
     }
 }
 
-impl<'a> Iterator for &mut CamIter<'a> {
-    type Item = Camera<'a>;
-    fn next(&mut self) -> Option<Self::Item> {
-        let res = self.list.get(self.index)?;
-        self.index += 1;
-        Some(res)
+/// Moves every client that's finished saying hello into `connections`, and drops the ones
+/// that got it wrong or took too long.
+fn finish_greetings(greetings: &mut Vec<Greeting>, connections: &mut Vec<Connection>, hello: &Hello) {
+    for mut greeting in std::mem::take(greetings) {
+        let res = greeting.reader.poll(&mut greeting.socket)
+            .and_then(|theirs| theirs.map(|theirs| hello.check(theirs)).transpose());
+        match res {
+            Ok(Some(_)) => connections.push(Connection::new((greeting.socket, greeting.addr))),
+            Ok(None) if greeting.since.elapsed() < HANDSHAKE_TIMEOUT => greetings.push(greeting),
+            // Peers from before the handshake existed just sit there.
+            Ok(None) => eprintln!("dropping connection: {}", HandshakeError::NotVistream),
+            Err(e) => eprintln!("dropping connection: {}", e),
+        }
     }
 }
 
+
 struct Connection {
     socket: UnixStream,
     #[allow(dead_code)]
     addr: SocketAddr,
-    // serializer: Serializer<UnixStream>,
+    reader: MessageReader,
+    // which of the camera's streams this gets frames from
+    channel: usize,
+    // what it wants them converted to, if not what the channel produces
+    format: Option<PixelFormat>,
     healthy: bool,
     active: bool,
 }
@@ -99,13 +94,28 @@ impl Connection {
         Connection {
             socket: conn.0,
             addr: conn.1,
+            reader: MessageReader::new(),
+            channel: 0,
+            format: None,
             healthy: true,
             active: false,
         }
     }
 
-    fn serializer(&mut self) -> Serializer<&mut UnixStream> {
-        Serializer::new(&mut self.socket)
+    fn send(&mut self, msg: &Message) {
+        match msg.encode() {
+            Ok(buf) => self.send_raw(&buf),
+            Err(_) => self.poison(),
+        }
+    }
+
+    /// For messages that are encoded once and sent to everyone.
+    fn send_raw(&mut self, buf: &[u8]) {
+        if self.socket.write_all(buf).is_err() {
+            // there may be occasions that this isn't grounds for poisoning,
+            // but I don't know of any
+            self.poison();
+        }
     }
 
     fn poison(&mut self) {
@@ -125,9 +135,40 @@ impl Connection {
     fn is_active(&self) -> bool {
         self.healthy && self.active
     }
+
+    fn status(&self, channels: &[Channel], framerate: Option<f64>) -> Status {
+        let channel = &channels[self.channel];
+        Status {
+            enabled: self.is_active(),
+            healthy: self.is_healthy(),
+            format: self.format.unwrap_or(channel.format),
+            width: channel.width as usize,
+            height: channel.height as usize,
+            channel: self.channel,
+            framerate,
+        }
+    }
 }
 
-pub fn launch(data: Launch) -> VisResult<()> {
+/// One of the camera's streams, as it comes from the camera.
+struct Channel {
+    format: PixelFormat,
+    width: u32,
+    height: u32,
+}
+
+fn describe_channels(capture: &dyn Capture) -> Vec<Channel> {
+    (0..capture.channels()).map(|channel| {
+        let (width, height) = capture.size(channel);
+        Channel {
+            format: capture.format(channel).to_string().parse().unwrap(),
+            width,
+            height,
+        }
+    }).collect()
+}
+
+pub fn launch(data: Launch, backend: &dyn Backend) -> VisResult<()> {
     // open questions:
     // - buffered? possible smoother framerate, but data is technically less "live"
 
@@ -143,15 +184,12 @@ pub fn launch(data: Launch) -> VisResult<()> {
     //    recycle request
     //  }
     // }
-   
-    let mgr = unwrap_or_fail!(4, CameraManager::new());
-    let cameras = mgr.cameras();
-    let mut camera_list = CamIter::new(&cameras);
-    
+
     let name = resolve_alias(&data.name)?;
-    let (cam, full_name) = match get_camera_by_name(&mut camera_list, &name)? {
-        Some(res) => res,
-        None => {fail!(6, "no camera exists with name '{}'", name);}
+    let full_name = match backend::matching_cameras(backend, &name)?.as_slice() {
+        [id] => id.clone(),
+        [] => {fail!(6, "no camera exists with name '{}'", name);}
+        _ => {fail!(5, "camera name \"{}\" is not unique", name);}
     };
     if is_camera_used(&full_name)? {
         // fail silently if server is already started
@@ -160,8 +198,7 @@ pub fn launch(data: Launch) -> VisResult<()> {
         }
         println!("{}", full_name);
         fail!(255, "camera {} already in use", full_name);
-    } 
-    let mut cam = unwrap_or_fail!(6, cam.acquire());
+    }
 
     use_camera(&full_name)?;
     println!("{}", full_name);
@@ -173,72 +210,36 @@ pub fn launch(data: Launch) -> VisResult<()> {
         };
     }));
 
-    let mut cfgs = cam.generate_configuration(&[StreamRole::VideoRecording]).unwrap();
-    let mut cfg = cfgs.get_mut(0).unwrap();
-
-    let format = translate_pixel_format(data.format);
-    cfg.set_pixel_format(format);
-    match get_closest_size(&cfg, &data) {
-        Some(size) => cfg.set_size(size),
-        None => {
-            fail_with_free!(10, &full_name, "{:?} is unsupported for {}", data.format, full_name);
-        },
-    };
-    cfg.set_buffer_count(data.buffer_count);
-
-    match cfgs.validate() {
-        CameraConfigurationStatus::Valid => {/* true no-op */}
-        CameraConfigurationStatus::Adjusted => {/* feels like something should be done, but no-op */}
-        CameraConfigurationStatus::Invalid => {
-            fail_with_free!(8, &full_name, "valid camera config could not be generated");
-        },
-    };
-    unwrap_or_fail_with_free!(8, &full_name, cam.configure(&mut cfgs));
+    // serving only ever stops on an error, which is what's returned here
+    let config = CaptureConfig::from(&data);
+    let res = backend.capture(&full_name, &config, &mut |capture| {
+        serve(&full_name, capture, &config)
+    });
+    let _ = free_camera(&full_name);
+    res
+}
 
-    let cfg = cfgs.get(0).unwrap();
-    let size = cfg.get_size();
+fn serve(id: &str, capture: &mut dyn Capture, config: &CaptureConfig) -> VisResult<()> {
+    let mut config = config.clone();
+    let mut channels = describe_channels(capture);
 
     // we're working on the assumption that the camera's id is unique.
     // This may not be true globally, but it almost certainly will be in a vast
     // majority of situations.
-    let addr = unwrap_or_fail_with_free!(7, &full_name, SocketAddr::from_abstract_name(cam.id()));
-    let listener = unwrap_or_fail_with_free!(7, &full_name, UnixListener::bind_addr(&addr));
-    unwrap_or_fail_with_free!(7, &full_name, listener.set_nonblocking(true));
+    let addr = unwrap_or_fail!(7, SocketAddr::from_abstract_name(id));
+    let listener = unwrap_or_fail!(7, UnixListener::bind_addr(&addr));
+    unwrap_or_fail!(7, listener.set_nonblocking(true));
 
     let mut connections: Vec<Connection> = Vec::new();
-    
-    // Allocate frame buffers for the stream
-    let mut alloc = FrameBufferAllocator::new(&cam);
-    let stream = cfg.stream().unwrap();
-    let buffers = alloc.alloc(&stream).unwrap();
-
-    let reqs = buffers.into_iter().map(|buf| {
-        let buf = MemoryMappedFrameBuffer::new(buf).unwrap();
-        let mut req = cam.create_request(None).unwrap();
-        req.add_buffer(&stream, buf).unwrap();
-        req
-    }).collect::<Vec<_>>();
-
-    // Completed capture requests are returned as a callback
-    let (tx, rx) = mpsc::channel();
-    cam.on_request_completed(move |req| {
-        tx.send(req).unwrap();
-    });
-
-    cam.start(None).unwrap();
-
-    // for req in reqs {
-    //     unwrap_or_fail!(8, cam.queue_request(req));
-    // }
-
-    let mut unused_reqs = reqs;
-    let pixel_format = data.format.to_string().parse().unwrap();
+    let mut greetings: Vec<Greeting> = Vec::new();
+    let mut hello = make_hello(&channels);
 
     loop {
         match listener.accept() {
             Ok(conn) => {
                 println!("connection from: {:?}", conn.1);
-                connections.push(Connection::new(conn));
+                // Clients say hello before anything else.
+                greetings.extend(Greeting::start(conn, &hello));
             }
             Err(e) => {
                 if e.kind() == std::io::ErrorKind::WouldBlock {
@@ -247,208 +248,215 @@ pub fn launch(data: Launch) -> VisResult<()> {
                     // a fraction of a second, just to save on processing, since
                     // incoming connections should be rare relative to the loop.
                 } else {
-                    fail_with_free!(7, &full_name, e.to_string());
+                    fail!(7, e.to_string());
                 }
             }
         }
-
-        for conn in connections.iter_mut() {
-            let mut buf = [0u8];
-            let action = match conn.socket.read(&mut buf) {
-                Ok(n) if n == 1 => {
-                    ClientMessage::from_id(buf[0])
-                },
-                Ok(_) => {continue;}
+        finish_greetings(&mut greetings, &mut connections, &hello);
+
+        // Reconfiguring affects everyone, so it waits until every connection has been heard.
+        let mut reconfigures = Vec::new();
+        for (i, conn) in connections.iter_mut().enumerate() {
+            let command = match conn.reader.poll(&mut conn.socket) {
+                Ok(Some(Message::Command(command))) => command,
+                Ok(Some(msg)) => {
+                    conn.send(&Message::Error(format!("expected a command, got {:?}", msg.kind())));
+                    continue;
+                }
+                Ok(None) => {continue;}
+                Err(e) if e.is_recoverable() => {
+                    conn.send(&Message::Error(e.to_string()));
+                    continue;
+                }
                 Err(e) => {
-                    if e.kind() == std::io::ErrorKind::WouldBlock {
-                        continue;
-                    }
                     dbg!(e);
                     conn.poison();
-                    None
+                    continue;
                 }
             };
-            println!("command got: {:?}", action);
+            println!("command got: {:?}", command);
 
-            match action {
-                Some(ClientMessage::Start) => {
+            match command {
+                Command::Start => {
                     conn.activate()
                 }
-                Some(ClientMessage::Stop) => {
+                Command::Stop => {
                     conn.deactivate()
                 }
-                Some(ClientMessage::Disconnect) => {
+                Command::Disconnect => {
                     conn.poison()
                 }
-                Some(ClientMessage::Status) => {
+                Command::Status => {
                     // TODO status stuff
-                    // - enabled
                     // - encoding
-                    // - frame size
-                    let msg = Status {
-                        enabled: conn.is_active(),
-                        healthy: conn.is_healthy(),
-                        format: data.format.to_string().parse().unwrap(),
-                        width: size.width as usize,
-                        height: size.height as usize,
-                    };
-                    match msg.serialize(&mut conn.serializer()) {
-                        Ok(_) => {/* no-op*/ }
-                        Err(_) => {
-                            // there may be occasions that this isn't grounds for poisoning, 
-                            // but I don't know of any
-                            conn.poison();
-                        }
+                    let msg = conn.status(&channels, capture.framerate());
+                    conn.send(&Message::Status(msg));
+                }
+                Command::Select { channel } => {
+                    if channel < channels.len() {
+                        conn.channel = channel;
+                        conn.format = conn.format.filter(|&format| convert::can_convert(channels[channel].format, format));
+                        let msg = conn.status(&channels, capture.framerate());
+                        conn.send(&Message::Status(msg));
+                    } else {
+                        conn.send(&Message::Error(format!("no channel {} (there are {})", channel, channels.len())));
                     }
                 }
-                _ => unreachable!()
+                Command::SetFormat { format } => {
+                    let native = channels[conn.channel].format;
+                    if convert::can_convert(native, format) {
+                        conn.format = (format != native).then_some(format);
+                        let msg = conn.status(&channels, capture.framerate());
+                        conn.send(&Message::Status(msg));
+                    } else {
+                        conn.send(&Message::Error(format!("{} frames can't be converted to {}", native, format)));
+                    }
+                }
+                Command::SetControl { name, value } => {
+                    // Controls are on the camera, so this changes things for every client.
+                    match capture.set_control(&name, value) {
+                        Ok(()) => conn.send(&Message::Ack),
+                        Err(e) => conn.send(&Message::Error(e)),
+                    }
+                }
+                Command::ListControls => {
+                    conn.send(&Message::Controls(capture.controls()));
+                }
+                Command::Reconfigure { format, width, height } => {
+                    reconfigures.push((i, format, width, height));
+                }
             }
         }
 
-        if connections.is_empty() || connections.iter().all(|conn| !conn.is_active()) {
-            // println!("no active connections (of {})", connections.len());
-            match rx.try_recv() {
-                Ok(mut req) => {
-                    // discard frame data
-                    req.reuse(ReuseFlag::REUSE_BUFFERS);
-                    unused_reqs.push(req);
-                }
-                Err(TryRecvError::Empty) => {/* do nothing */}
-                Err(TryRecvError::Disconnected) => {fail_with_free!(9, &full_name, "camera disconnected");}
+        // Each one is for the channel of whoever asked.
+        for (i, format, new_width, new_height) in reconfigures {
+            let channel = connections[i].channel;
+            let mut new_config = config.clone();
+            let stream = &mut new_config.streams[channel];
+            if let Some(format) = format {
+                let Some(format) = FourCC::from_pixel_format(format) else {
+                    connections[i].send(&Message::Error("cameras can't produce that format".to_string()));
+                    continue;
+                };
+                stream.format = format;
+            }
+            (stream.width, stream.height) = match (new_width, new_height) {
+                (None, None) => (Some(channels[channel].width), Some(channels[channel].height)),
+                size => size,
+            };
+            if let Err(e) = capture.reconfigure(&new_config) {
+                connections[i].send(&Message::Error(format!("couldn't reconfigure: {}", e)));
+                continue;
+            }
+            config = new_config;
+            channels = describe_channels(capture);
+            hello = make_hello(&channels);
+            let changed = &channels[channel];
+            println!("reconfigured channel {} to {} {}x{}", channel, changed.format, changed.width, changed.height);
+
+            // The camera may have had to adjust the other channels too, so everyone hears.
+            // Anyone whose format can't be made anymore gets what the channel produces, and
+            // finds out from the status.
+            for conn in connections.iter_mut() {
+                let native = channels[conn.channel].format;
+                conn.format = conn.format.filter(|&format| format != native && convert::can_convert(native, format));
+                let status = conn.status(&channels, capture.framerate());
+                conn.send(&Message::Status(status));
             }
+            connections[i].send(&Message::Ack);
+        }
+
+        let wanted = (0..channels.len())
+            .map(|channel| connections.iter().any(|conn| conn.is_active() && conn.channel == channel))
+            .collect::<Vec<_>>();
+        if !wanted.contains(&true) {
+            // println!("no active connections (of {})", connections.len());
+            capture.idle()?;
             connections = connections.into_iter().filter(|conn| conn.is_healthy()).collect();
             std::thread::sleep(std::time::Duration::from_millis(20));
             continue;
         }
-        // println!("{} active connections", connections.iter().map(|conn| conn.is_active()).count());
 
         // we have an active connection at this point
-        while !unused_reqs.is_empty() {
-            let req = unused_reqs.pop().unwrap();
-            unwrap_or_fail_with_free!(8, &full_name, cam.queue_request(req));
-        }
-
-        let mut req = match rx.recv_timeout(std::time::Duration::from_millis(10)) {
-            Ok(req) => {req},
-            Err(RecvTimeoutError::Timeout) => {continue;}
-            Err(RecvTimeoutError::Disconnected) => {fail_with_free!(9, &full_name, "camera disconnected");}
+        let Some(data) = capture.next_frame(std::time::Duration::from_millis(10), &wanted)? else {
+            continue;
         };
-        
-        // get frame
-        
-        if connections.iter().any(|conn| conn.is_active()) {
-            let frame_buffer: &MemoryMappedFrameBuffer<FrameBuffer> = req.buffer(&stream).unwrap();
-            let planes = frame_buffer.data();
-            let frame_data = planes.get(0).unwrap();
-            let bytes_used = frame_buffer.metadata().unwrap().planes().get(0).unwrap().bytes_used as usize;
-
-            let data = frame_data[..bytes_used].to_vec();
-
-            // .to_vec()println!("server: frame len: {}", data.len());
-            let frame = Frame {
-                format: pixel_format,
-                width: size.width,
-                height: size.height,
-                data,
+
+        for (channel, data) in data.into_iter().enumerate() {
+            let Some(mut data) = data else {
+                continue;
             };
+            let Channel { format: native, width, height } = channels[channel];
+
+            // Each format is only made once, however many connections want it. The frame as it
+            // came is last, so it can be moved instead of copied.
+            let mut formats = Vec::new();
+            for conn in connections.iter().filter(|conn| conn.is_active() && conn.channel == channel) {
+                let format = conn.format.unwrap_or(native);
+                if !formats.contains(&format) {
+                    formats.push(format);
+                }
+            }
+            formats.sort_by_key(|&format| format == native);
 
-            // println!("server: msg len: {}", rmp_serde::to_vec(&frame).unwrap().len());
-            for conn in connections.iter_mut() {
-                match frame.serialize(&mut conn.serializer()) {
-                    Ok(_) => {/* no-op*/ 
-                        // println!("server: frame sent");
+            for format in formats {
+                let data = if format == native {
+                    std::mem::take(&mut data)
+                } else {
+                    match convert::convert(&data, native, format, width, height) {
+                        Ok(converted) => converted,
+                        Err(e) => {
+                            eprintln!("dropping {} frame: {}", format, e);
+                            continue;
+                        }
                     }
-                    Err(_) => {
-                        // there may be occasions that this isn't grounds for poisoning, 
-                        // but I don't know of any
-                        conn.poison();
+                };
+                let frame = Message::Frame(Frame {
+                    format,
+                    width,
+                    height,
+                    data,
+                });
+                let buf = match frame.encode() {
+                    Ok(buf) => buf,
+                    Err(e) => {
+                        eprintln!("dropping {} frame: {}", format, e);
+                        continue;
                     }
                 };
-            }
-            // pruning dead connections
-            connections = connections.into_iter().filter(|conn| conn.is_healthy()).collect();
-
-        }
-        req.reuse(ReuseFlag::REUSE_BUFFERS);
-        unwrap_or_fail_with_free!(8, &full_name, cam.queue_request(req));
-    }
-
-    // let _ = free_camera(&name);
-    // Ok(())
-    
-}
 
-// boy this is a crapload of typing weirdness to humor the lifetime restrictions of libcamera-rs
-fn get_camera_by_name<'a>(camera_list: &mut CamIter<'a>, name: &str) -> 
-            VisResult<Option<(Camera<'a>, String)>> {
-    let mut cam: Option<Camera> = None;
-    
-    for camera in camera_list {
-        if name == camera.id() || name == *camera.properties().get::<properties::Model>().unwrap() {
-            if cam.is_some() {
-                fail!(5, "camera name \"{}\" is not unique", name);
+                for conn in connections.iter_mut()
+                    .filter(|conn| conn.is_active() && conn.channel == channel && conn.format.unwrap_or(native) == format)
+                {
+                    conn.send_raw(&buf);
+                }
             }
-            cam = Some(camera);
         }
+        // pruning dead connections
+        connections = connections.into_iter().filter(|conn| conn.is_healthy()).collect();
     }
-
-    Ok(cam.map(|c| {
-        let name = c.id().to_string();
-        (c, name)
-    }))
 }
 
-fn translate_pixel_format(fourcc: FourCC) -> pf::PixelFormat {
-    let format: PixelFormat = fourcc.to_string().parse().unwrap();
-    pf::PixelFormat::new(u32::from_le_bytes(format.fourcc()), 0)
-}
-
-fn get_closest_size<'a>(cfg: &'a StreamConfigurationRef<'a>, config: &Launch) -> Option<Size> {
-    let format = translate_pixel_format(config.format);
-    let sizes = cfg.formats().sizes(format);
-
-    if config.width.is_none() && config.height.is_none() {
-        // let true_width = sizes.iter().max_by_key(|s| s.width)?.width;
-        // sizes.into_iter().filter(|s| s.width == true_width).max_by_key(|s| s.height)
-        sizes.into_iter().max_by_key(|s| s.height * s.width)
-    } else if config.width.is_none() {
-        // sort solely based on height
-        let height = config.height.unwrap();
-        let true_height = sizes.iter().min_by_key(|s| {
-            s.height.abs_diff(height)
-        })?.height;
-
-        sizes.into_iter().filter(|s| s.height == true_height).max_by_key(|s| {
-            s.width
-        })
-    } else if config.height.is_none() {
-        // sort solely based on width
-        let width = config.width.unwrap();
-        let true_width = sizes.iter().min_by_key(|s| {
-            s.width.abs_diff(width)
-        })?.width;
-
-        sizes.into_iter().filter(|s| s.width == true_width).max_by_key(|s| {
-            s.height
-        })
-    } else {
-        // sort based on lowest dw and dh 
-        let width = config.width.unwrap();
-        let height = config.height.unwrap();
-        let true_width = sizes.iter().min_by_key(|s| {
-            s.width.abs_diff(width)
-        })?.width;
-
-        sizes.into_iter().filter(|s| s.width == true_width).min_by_key(|s| {
-            s.height.abs_diff(height)
-        })
-        // sizes.into_iter().min_by_key(|s| {
-        //     s.width.abs_diff(width) + s.height.abs_diff(height)
-        // })
+fn make_hello(channels: &[Channel]) -> Hello {
+    let mut formats = Vec::new();
+    let mut encodings = Vec::new();
+    // everything any channel can be converted to counts
+    let reachable = channels.iter().flat_map(|channel| {
+        std::iter::once(channel.format)
+            .chain(convert::TARGETS.into_iter().filter(|&format| convert::can_convert(channel.format, format)))
+    });
+    for format in reachable {
+        let encoding = if format == PixelFormat::MJPEG { Encoding::Jpeg } else { Encoding::Raw };
+        if !formats.contains(&format) {
+            formats.push(format);
+        }
+        if !encodings.contains(&encoding) {
+            encodings.push(encoding);
+        }
     }
+    Hello::new(formats, encodings)
 }
 
-
 fn use_camera(name: &str) -> VisResult<()> {
     let known_file = get_or_make_known_camera_file()?;
     let mut f = unwrap_or_fail!(1, File::options().create(true).append(true).open(known_file));
@@ -1,6 +1,7 @@
 use serde::{Serialize, Deserialize};
 use serde_repr::{Serialize_repr, Deserialize_repr};
 
+use std::io::{self, Read, Write};
 use std::str::FromStr;
 
 
@@ -13,6 +14,14 @@ pub enum PixelFormat {
     BGRA,
     YUYV,
     MJPEG,
+    SRGGB8,
+    SRGGB10,
+    SRGGB12,
+    SRGGB10P,
+    SRGGB12P,
+    /// 8-bit single channel, for grayscale images and masks. Cameras don't produce this,
+    /// but it can be streamed.
+    Luma,
 }
 
 impl PixelFormat {
@@ -29,7 +38,46 @@ impl PixelFormat {
             PixelFormat::RGBA => *b"BA24",
             PixelFormat::BGRA => *b"RA24",
             PixelFormat::YUYV => *b"YUYV",
-            PixelFormat::MJPEG => *b"MJPG"
+            PixelFormat::MJPEG => *b"MJPG",
+            PixelFormat::SRGGB8 => *b"RGGB",
+            PixelFormat::SRGGB10 | PixelFormat::SRGGB10P => *b"RG10",
+            PixelFormat::SRGGB12 | PixelFormat::SRGGB12P => *b"RG12",
+            PixelFormat::Luma => *b"R8  ",
+        }
+    }
+
+    /// Returns the DRM format modifier that goes along with `fourcc`. The CSI-2 packed Bayer
+    /// formats share a FourCC with their unpacked versions, and only differ by this.
+    pub fn modifier(&self) -> u64 {
+        // fourcc_mod_code(MIPI, 1), from libcamera's formats.h
+        const MIPI_FORMAT_MOD_CSI2_PACKED: u64 = (0x0a << 56) | 1;
+        match self {
+            PixelFormat::SRGGB10P | PixelFormat::SRGGB12P => MIPI_FORMAT_MOD_CSI2_PACKED,
+            _ => 0,
+        }
+    }
+
+    /// Whether cameras can produce this themselves. Anything else only comes from converting
+    /// something that they can.
+    pub fn is_camera_format(&self) -> bool {
+        !matches!(self, PixelFormat::Luma)
+    }
+
+    pub fn is_bayer(&self) -> bool {
+        matches!(self, PixelFormat::SRGGB8 | PixelFormat::SRGGB10 | PixelFormat::SRGGB12
+                     | PixelFormat::SRGGB10P | PixelFormat::SRGGB12P)
+    }
+}
+
+/// Formats as the name accepted by `FromStr`, which is the FourCC for everything but the
+/// packed Bayer formats. Those use their V4L2 names, since the FourCC alone is ambiguous.
+impl std::fmt::Display for PixelFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PixelFormat::SRGGB10P => write!(f, "pRAA"),
+            PixelFormat::SRGGB12P => write!(f, "pRCC"),
+            PixelFormat::Luma => write!(f, "R8"),
+            _ => write!(f, "{}", String::from_utf8_lossy(&self.fourcc())),
         }
     }
 }
@@ -48,6 +96,12 @@ impl FromStr for PixelFormat {
             "BA24" => Ok(PixelFormat::RGBA),
             "YUYV" => Ok(PixelFormat::YUYV),
             "MJPG" => Ok(PixelFormat::MJPEG),
+            "RGGB" | "SRGGB8" => Ok(PixelFormat::SRGGB8),
+            "RG10" | "SRGGB10" => Ok(PixelFormat::SRGGB10),
+            "RG12" | "SRGGB12" => Ok(PixelFormat::SRGGB12),
+            "PRAA" | "SRGGB10P" | "SRGGB10_CSI2P" => Ok(PixelFormat::SRGGB10P),
+            "PRCC" | "SRGGB12P" | "SRGGB12_CSI2P" => Ok(PixelFormat::SRGGB12P),
+            "R8" | "R8  " | "GREY" | "LUMA" => Ok(PixelFormat::Luma),
             _ => Err(format!("not a recognized fourcc code ({})", s)),
         }
     }
@@ -63,34 +117,68 @@ pub struct Frame {
 }
 
 
-#[derive(Clone, Copy, Debug, Serialize_repr, Deserialize_repr)]
-#[repr(u8)]
-pub enum ClientMessage {
+/// Something a client wants the camera server to do. Sent as `Message::Command`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Command {
     Start,
     Stop,
     Disconnect,
     Status,
+    /// Sets a camera control, by its libcamera name (e.g. "ExposureTime"). Answered with
+    /// `Message::Ack`, or `Message::Error` if the camera doesn't take it.
+    SetControl {
+        name: String,
+        value: ControlValue,
+    },
+    /// Answered with `Message::Controls`.
+    ListControls,
+    /// Switches to another of the camera's streams, if it was launched with more than one.
+    /// Connections start on channel 0. Answered with the new channel's `Status`.
+    Select {
+        channel: usize,
+    },
+    /// Asks for frames in `format`, which the server converts to if the channel doesn't
+    /// produce it. Answered with `Status`, or `Message::Error` if there's no conversion.
+    SetFormat {
+        format: PixelFormat,
+    },
+    /// Restarts the camera with a new format and/or size for this connection's channel,
+    /// leaving out whatever should stay the same. The size is only a request, like at launch.
+    /// Every client gets the new `Status`, then this one gets `Message::Ack`.
+    Reconfigure {
+        format: Option<PixelFormat>,
+        width: Option<u32>,
+        height: Option<u32>,
+    },
+}
+
+/// The value of a camera control. Integer controls take `Int` whatever their size on the
+/// camera's end.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ControlValue {
+    Bool(bool),
+    Int(i64),
+    Float(f32),
 }
 
-impl ClientMessage {
-    pub fn id(&self) -> u8 {
+impl std::fmt::Display for ControlValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            ClientMessage::Start => 0,
-            ClientMessage::Stop => 1,
-            ClientMessage::Disconnect => 2,
-            ClientMessage::Status => 3,
+            ControlValue::Bool(v) => write!(f, "{}", v),
+            ControlValue::Int(v) => write!(f, "{}", v),
+            ControlValue::Float(v) => write!(f, "{}", v),
         }
     }
+}
 
-    pub fn from_id(id: u8) -> Option<ClientMessage> {
-        match id {
-            0 => Some(ClientMessage::Start),
-            1 => Some(ClientMessage::Stop),
-            2 => Some(ClientMessage::Disconnect),
-            3 => Some(ClientMessage::Status),
-            _ => None
-        }
-    }
+/// A control a camera takes, and whatever is known about its range. Not every backend can
+/// tell, so any of these can be missing.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ControlInfo {
+    pub name: String,
+    pub min: Option<ControlValue>,
+    pub max: Option<ControlValue>,
+    pub default: Option<ControlValue>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -100,6 +188,12 @@ pub struct Status {
     pub format: PixelFormat,
     pub width: usize,
     pub height: usize,
+    /// Which of the camera's streams this is about
+    #[serde(default)]
+    pub channel: usize,
+    /// Frames per second the camera is actually running at, if the server knows yet.
+    #[serde(default)]
+    pub framerate: Option<f64>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -123,3 +217,185 @@ impl std::fmt::Display for CameraListing {
         Ok(())
     }
 }
+
+/// The type byte at the front of every message on the camera socket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MessageKind {
+    Command = 0,
+    Status = 1,
+    Frame = 2,
+    Error = 3,
+    Ack = 4,
+    Controls = 5,
+}
+
+impl MessageKind {
+    pub fn from_id(id: u8) -> Option<MessageKind> {
+        match id {
+            0 => Some(MessageKind::Command),
+            1 => Some(MessageKind::Status),
+            2 => Some(MessageKind::Frame),
+            3 => Some(MessageKind::Error),
+            4 => Some(MessageKind::Ack),
+            5 => Some(MessageKind::Controls),
+            _ => None,
+        }
+    }
+}
+
+// A 4K RGBA frame is ~33MB, so this leaves plenty of room while still catching garbage.
+const MAX_MESSAGE_LEN: usize = 256 * 1024 * 1024;
+const HEADER_LEN: usize = 5;
+
+/// Everything sent over the camera socket, in either direction. On the wire, each message is
+/// its `MessageKind` byte, a big-endian u32 payload length, then the msgpack payload.
+///
+/// Clients send `Command`s. The server pushes a `Frame` for every captured frame while
+/// started, answers the commands that ask for something, and sends an `Error` for commands
+/// it couldn't carry out.
+pub enum Message {
+    Command(Command),
+    Status(Status),
+    Frame(Frame),
+    Error(String),
+    /// A command that doesn't otherwise have an answer went through.
+    Ack,
+    Controls(Vec<ControlInfo>),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum MessageError {
+    #[error("unknown message kind ({0})")]
+    UnknownKind(u8),
+    #[error("malformed {0:?} message")]
+    Malformed(MessageKind),
+    #[error("message too long ({0} bytes)")]
+    TooLong(usize),
+    #[error(transparent)]
+    IO(#[from] io::Error),
+}
+
+impl MessageError {
+    /// Whether the stream is still usable after this error. Bad payloads are skipped over
+    /// whole, so only IO errors and bogus lengths leave it out of sync.
+    pub fn is_recoverable(&self) -> bool {
+        matches!(self, MessageError::UnknownKind(_) | MessageError::Malformed(_))
+    }
+}
+
+impl Message {
+    pub fn kind(&self) -> MessageKind {
+        match self {
+            Message::Command(_) => MessageKind::Command,
+            Message::Status(_) => MessageKind::Status,
+            Message::Frame(_) => MessageKind::Frame,
+            Message::Error(_) => MessageKind::Error,
+            Message::Ack => MessageKind::Ack,
+            Message::Controls(_) => MessageKind::Controls,
+        }
+    }
+
+    /// The full message, header included, ready to be written to any number of peers.
+    pub fn encode(&self) -> Result<Vec<u8>, MessageError> {
+        let mut buf = vec![self.kind() as u8, 0, 0, 0, 0];
+        let res = match self {
+            Message::Command(cmd) => rmp_serde::encode::write(&mut buf, cmd),
+            Message::Status(status) => rmp_serde::encode::write(&mut buf, status),
+            Message::Frame(frame) => rmp_serde::encode::write(&mut buf, frame),
+            Message::Error(msg) => rmp_serde::encode::write(&mut buf, msg),
+            Message::Ack => rmp_serde::encode::write(&mut buf, &()),
+            Message::Controls(controls) => rmp_serde::encode::write(&mut buf, controls),
+        };
+        res.map_err(|_| MessageError::Malformed(self.kind()))?;
+        let len = buf.len() - HEADER_LEN;
+        if len > MAX_MESSAGE_LEN {
+            return Err(MessageError::TooLong(len));
+        }
+        buf[1..HEADER_LEN].copy_from_slice(&(len as u32).to_be_bytes());
+        Ok(buf)
+    }
+
+    pub fn write_to<W: Write>(&self, w: &mut W) -> Result<(), MessageError> {
+        w.write_all(&self.encode()?)?;
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Blocks until a whole message has been read.
+    pub fn read_from<R: Read>(r: &mut R) -> Result<Message, MessageError> {
+        let mut header = [0u8; HEADER_LEN];
+        r.read_exact(&mut header)?;
+        let len = payload_len(&header)?;
+        let mut payload = vec![0u8; len];
+        r.read_exact(&mut payload)?;
+        Message::decode(header[0], &payload)
+    }
+
+    fn decode(kind: u8, payload: &[u8]) -> Result<Message, MessageError> {
+        let kind = MessageKind::from_id(kind).ok_or(MessageError::UnknownKind(kind))?;
+        let res = match kind {
+            MessageKind::Command => rmp_serde::from_slice(payload).map(Message::Command),
+            MessageKind::Status => rmp_serde::from_slice(payload).map(Message::Status),
+            MessageKind::Frame => rmp_serde::from_slice(payload).map(Message::Frame),
+            MessageKind::Error => rmp_serde::from_slice(payload).map(Message::Error),
+            MessageKind::Ack => rmp_serde::from_slice::<()>(payload).map(|_| Message::Ack),
+            MessageKind::Controls => rmp_serde::from_slice(payload).map(Message::Controls),
+        };
+        res.map_err(|_| MessageError::Malformed(kind))
+    }
+}
+
+fn payload_len(header: &[u8]) -> Result<usize, MessageError> {
+    let len = u32::from_be_bytes(header[1..HEADER_LEN].try_into().unwrap()) as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(MessageError::TooLong(len));
+    }
+    Ok(len)
+}
+
+/// Puts messages back together from a socket with a read timeout, for loops that can't
+/// block on any one peer.
+#[derive(Default)]
+pub struct MessageReader {
+    buf: Vec<u8>,
+}
+
+impl MessageReader {
+    pub fn new() -> MessageReader {
+        MessageReader::default()
+    }
+
+    /// Reads whatever has arrived, and returns the next message if there's a whole one.
+    /// Timeouts just mean there's nothing yet, and give `Ok(None)`.
+    pub fn poll<R: Read>(&mut self, r: &mut R) -> Result<Option<Message>, MessageError> {
+        if let Some(msg) = self.take()? {
+            return Ok(Some(msg));
+        }
+        let mut chunk = [0u8; 4096];
+        match r.read(&mut chunk) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        }
+        self.take()
+    }
+
+    fn take(&mut self) -> Result<Option<Message>, MessageError> {
+        if self.buf.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        let len = payload_len(&self.buf)?;
+        if self.buf.len() < HEADER_LEN + len {
+            return Ok(None);
+        }
+        let kind = self.buf[0];
+        // the message is consumed even if it doesn't decode, so the next one still lines up
+        let rest = self.buf.split_off(HEADER_LEN + len);
+        let msg = std::mem::replace(&mut self.buf, rest);
+        Message::decode(kind, &msg[HEADER_LEN..]).map(Some)
+    }
+}
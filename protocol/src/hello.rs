@@ -0,0 +1,192 @@
+//! The hello both ends of a connection send first, on both the camera socket and streams, so
+//! mismatched versions fail up front instead of misparsing each other.
+//!
+//! On the wire, a hello is `b"VSHI"`, a big-endian u32 length, then the msgpack `Hello`.
+
+use serde::{Serialize, Deserialize};
+use std::io::{self, Read, Write};
+
+use crate::camera::PixelFormat;
+use crate::stream::Encoding;
+
+/// Bumped whenever either protocol changes in a way older peers can't handle.
+pub const PROTOCOL_VERSION: u16 = 2;
+
+const MAGIC: &[u8; 4] = b"VSHI";
+// Way more than any real hello, but small enough that garbage can't make us allocate much.
+const MAX_LEN: usize = 64 * 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hello {
+    pub version: u16,
+    /// Pixel formats this end can send or take.
+    pub formats: Vec<PixelFormat>,
+    /// Frame encodings this end can send or take.
+    pub encodings: Vec<Encoding>,
+    /// Optional extras, for things that can be added without a version bump.
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum HandshakeError {
+    #[error("peer didn't send a vistream hello (is it an older version?)")]
+    NotVistream,
+    #[error("malformed hello from peer")]
+    Malformed,
+    #[error("protocol version mismatch: this end speaks v{ours}, the peer speaks v{theirs}")]
+    Version {
+        ours: u16,
+        theirs: u16,
+    },
+    #[error("no pixel format in common: this end has {ours:?}, the peer has {theirs:?}")]
+    NoCommonFormat {
+        ours: Vec<PixelFormat>,
+        theirs: Vec<PixelFormat>,
+    },
+    #[error("no frame encoding in common: this end has {ours:?}, the peer has {theirs:?}")]
+    NoCommonEncoding {
+        ours: Vec<Encoding>,
+        theirs: Vec<Encoding>,
+    },
+    #[error(transparent)]
+    IO(#[from] io::Error),
+}
+
+impl Hello {
+    pub fn new(formats: Vec<PixelFormat>, encodings: Vec<Encoding>) -> Hello {
+        Hello {
+            version: PROTOCOL_VERSION,
+            formats,
+            encodings,
+            features: Vec::new(),
+        }
+    }
+
+    pub fn with_feature(mut self, feature: &str) -> Hello {
+        self.features.push(feature.to_string());
+        self
+    }
+
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+
+    pub fn write_to<W: Write>(&self, w: &mut W) -> Result<(), HandshakeError> {
+        let body = rmp_serde::to_vec(self).map_err(|_| HandshakeError::Malformed)?;
+        let mut buf = Vec::with_capacity(body.len() + 8);
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&body);
+        w.write_all(&buf)?;
+        w.flush()?;
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(r: &mut R) -> Result<Hello, HandshakeError> {
+        let mut header = [0u8; 8];
+        // Peers from before the handshake existed just sit there, or send something short.
+        r.read_exact(&mut header).map_err(|e| match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::UnexpectedEof => HandshakeError::NotVistream,
+            _ => e.into(),
+        })?;
+        if &header[0..4] != MAGIC {
+            return Err(HandshakeError::NotVistream);
+        }
+        let len = u32::from_be_bytes(header[4..8].try_into().unwrap()) as usize;
+        if len > MAX_LEN {
+            return Err(HandshakeError::Malformed);
+        }
+        let mut body = vec![0u8; len];
+        r.read_exact(&mut body)?;
+        rmp_serde::from_slice(&body).map_err(|_| HandshakeError::Malformed)
+    }
+
+    /// Checks that the peer's hello is one this end can talk to, handing it back if so. Besides
+    /// the version, the two have to share a format and an encoding. An empty list means that
+    /// end doesn't care (or has no protocol format, like H264), so it matches anything.
+    pub fn check(&self, theirs: Hello) -> Result<Hello, HandshakeError> {
+        if theirs.version != self.version {
+            return Err(HandshakeError::Version {
+                ours: self.version,
+                theirs: theirs.version,
+            });
+        }
+        if !overlaps(&self.formats, &theirs.formats) {
+            return Err(HandshakeError::NoCommonFormat {
+                ours: self.formats.clone(),
+                theirs: theirs.formats,
+            });
+        }
+        if !overlaps(&self.encodings, &theirs.encodings) {
+            return Err(HandshakeError::NoCommonEncoding {
+                ours: self.encodings.clone(),
+                theirs: theirs.encodings,
+            });
+        }
+        Ok(theirs)
+    }
+
+    /// Sends this hello, then reads the peer's and checks that the two can talk to each
+    /// other. Sending first means the peer gets to see why, even if this end bails.
+    pub fn exchange<S: Read + Write>(&self, stream: &mut S) -> Result<Hello, HandshakeError> {
+        self.write_to(stream)?;
+        let theirs = Hello::read_from(stream)?;
+        self.check(theirs)
+    }
+}
+
+fn overlaps<T: PartialEq>(ours: &[T], theirs: &[T]) -> bool {
+    ours.is_empty() || theirs.is_empty() || ours.iter().any(|x| theirs.contains(x))
+}
+
+/// Reads a peer's hello from a socket with a read timeout (or a nonblocking one), for servers
+/// that can't stop serving everyone else to wait on a new peer. Only the hello itself is read,
+/// so whatever the peer sends after it is left on the socket.
+#[derive(Default)]
+pub struct HelloReader {
+    buf: Vec<u8>,
+}
+
+impl HelloReader {
+    pub fn new() -> HelloReader {
+        HelloReader::default()
+    }
+
+    /// Reads whatever has arrived, and returns the hello once it's all in. Timeouts just mean
+    /// there's nothing yet, and give `Ok(None)`. It's up to the caller how long to wait.
+    pub fn poll<R: Read>(&mut self, r: &mut R) -> Result<Option<Hello>, HandshakeError> {
+        loop {
+            let wanted = match self.buf.len() {
+                n if n < 8 => 8,
+                _ => {
+                    let len = u32::from_be_bytes(self.buf[4..8].try_into().unwrap()) as usize;
+                    if len > MAX_LEN {
+                        return Err(HandshakeError::Malformed);
+                    }
+                    8 + len
+                }
+            };
+            if self.buf.len() >= 8 && self.buf.len() == wanted {
+                return rmp_serde::from_slice(&self.buf[8..]).map(Some).map_err(|_| HandshakeError::Malformed);
+            }
+
+            let mut chunk = [0u8; 1024];
+            let n = (wanted - self.buf.len()).min(chunk.len());
+            match r.read(&mut chunk[..n]) {
+                // Peers from before the handshake existed might hang up on something they
+                // don't understand.
+                Ok(0) => return Err(HandshakeError::NotVistream),
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                    return Ok(None);
+                }
+                Err(e) => return Err(e.into()),
+            }
+            let magic_len = self.buf.len().min(MAGIC.len());
+            if self.buf[..magic_len] != MAGIC[..magic_len] {
+                return Err(HandshakeError::NotVistream);
+            }
+        }
+    }
+}
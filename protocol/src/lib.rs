@@ -1,3 +1,4 @@
 pub mod camera;
 pub mod fs;
 pub mod stream;
+pub mod hello;
@@ -1,6 +1,8 @@
 use serde::{Serialize, Deserialize};
 use serde_repr::{Serialize_repr, Deserialize_repr};
 
+use crate::camera::PixelFormat;
+
 #[derive(Debug, Default, Deserialize, Serialize, Clone, Copy)]
 pub struct LocationData {
     pub x: f64,
@@ -144,13 +146,47 @@ pub struct Status {
     pub enabled: bool,
     pub healthy: bool,
     pub framerate: f64,
+    /// Current quality of the stream's lossy encoder, if it has one.
+    #[serde(default)]
+    pub quality: Option<u8>,
+}
+
+/// How the `data` of a stream `Frame` is encoded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize_repr, Deserialize_repr)]
+#[repr(u8)]
+pub enum Encoding {
+    #[default]
+    Jpeg,
+    Png,
+    Qoi,
+    /// Pixel data exactly as the source produced it, with no container.
+    Raw,
+    /// Annex B byte stream. Only frames with `keyframe` set can be decoded on their own.
+    H264,
+}
+
+impl Encoding {
+    pub const ALL: [Encoding; 5] = [Encoding::Jpeg, Encoding::Png, Encoding::Qoi, Encoding::Raw, Encoding::H264];
 }
 
-// No format listed. Will always be jpg
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Frame {
     pub width: u32,
     pub height: u32,
     #[serde(with = "serde_bytes")]
-    pub data: Vec<u8>
+    pub data: Vec<u8>,
+    // Older servers don't send this, and only ever sent jpg.
+    #[serde(default)]
+    pub encoding: Encoding,
+    #[serde(default = "keyframe_default")]
+    pub keyframe: bool,
+    /// Pixel format of the frame before it was encoded. Missing from older servers, and for
+    /// formats with no `PixelFormat` equivalent, like H264.
+    #[serde(default)]
+    pub format: Option<PixelFormat>,
+}
+
+// Everything but H264 is intra-only, so a missing flag means a keyframe.
+fn keyframe_default() -> bool {
+    true
 }
@@ -7,6 +7,7 @@ use std::os::unix::net::{SocketAddr, UnixStream};
 use std::os::linux::net::{SocketAddrExt};
 
 use std::sync::{RwLock, Arc};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
 use std::sync::atomic::{AtomicUsize, AtomicBool, Ordering};
 
 use std::process::{Command};
@@ -20,8 +21,9 @@ use std::io::{Write};
 use rmp_serde::decode::Deserializer;
 use serde::{Deserialize};
 
-use vistream_protocol::camera::{self, ClientMessage, Status};
-use vistream_protocol::stream::LocationData;
+use vistream_protocol::camera::{self, ControlInfo, ControlValue, Message, Status};
+use vistream_protocol::stream::{LocationData, Encoding};
+use vistream_protocol::hello::Hello;
 // use vistream_protocol::network::{BufferedStream};
 // use vistream_protocol::fs::*;
 
@@ -55,17 +57,27 @@ pub trait FrameSource<F: frame::PixelFormat> {
     fn start(&mut self) -> Result<()>;
     fn stop(&mut self) -> Result<()>;
     fn last_frame_id(&self) -> usize;
+
+    /// Current quality of the lossy encoder producing these frames, if there is one. Sources
+    /// that wrap another source should pass this through.
+    fn encoder_quality(&self) -> Option<u8> {
+        None
+    }
 }
 
 #[cfg(target_os = "linux")]
 #[derive(Default, Clone)]
 pub struct CameraConfig {
     buffer_count: Option<u32>,
+    framerate: Option<f64>,
     width: Option<u32>,
     height: Option<u32>,
     resize: bool,
     server_exe: Option<String>,
     conn_timeout: Option<std::time::Duration>,
+    channel: usize,
+    // (format, width, height) of each stream after the main one, if this launches the camera
+    extra_channels: Vec<(vistream_protocol::camera::PixelFormat, u32, u32)>,
 }
 
 #[cfg(target_os = "linux")]
@@ -79,6 +91,13 @@ impl CameraConfig {
         self
     }
 
+    /// The most frames per second the camera should produce, if this ends up launching it.
+    /// 0 leaves it up to the camera.
+    pub fn framerate(&mut self, framerate: f64) -> &mut Self {
+        self.framerate = (framerate > 0.0).then_some(framerate);
+        self
+    }
+
     pub fn width(&mut self, width: u32) -> &mut Self {
         self.width = (width != 0).then_some(width);
         self
@@ -103,6 +122,21 @@ impl CameraConfig {
         self.conn_timeout = Some(timeout);
         self
     }
+
+    /// Which of the camera's streams to take frames from. 0, the default, is the main one,
+    /// configured with `width` and `height`; the rest are the ones from `add_channel`, in order.
+    pub fn channel(&mut self, channel: usize) -> &mut Self {
+        self.channel = channel;
+        self
+    }
+
+    /// Asks for another stream alongside the main one, if this ends up launching the camera.
+    /// Meant for things like running a locator on a small stream while recording a big one.
+    /// Cameras that are already running keep whatever channels they were launched with.
+    pub fn add_channel(&mut self, format: vistream_protocol::camera::PixelFormat, width: u32, height: u32) -> &mut Self {
+        self.extra_channels.push((format, width, height));
+        self
+    }
 }
 
 pub enum Worker {
@@ -128,7 +162,7 @@ impl Worker {
 
     pub fn is_finished(&self) -> bool {
         match self {
-            Worker::Worker{ref worker, ..} => worker.as_ref().map(|w| w.is_finished()).unwrap(),
+            Worker::Worker{ref worker, ..} => worker.as_ref().is_none_or(|w| w.is_finished()),
             Worker::Done => true,
         }
     }
@@ -172,11 +206,16 @@ impl Worker {
     }
 }
 
+#[cfg(target_os = "linux")]
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
 #[cfg(target_os = "linux")]
 pub struct Camera<F: frame::PixelFormat + 'static> {
     // unambiguous camera name (most likely the id) 
     name: String,
     control: UnixStream,
+    // answers to commands, as sorted out from frames by the worker
+    replies: Receiver<Message>,
     frame_worker: Worker,
     // type explanation (for my future self)
     // Arc -  allows sharing between threads (the reader from the server),
@@ -187,8 +226,8 @@ pub struct Camera<F: frame::PixelFormat + 'static> {
     last_frame: Arc<RwLock<Option<Arc<Frame<F>>>>>,
     last_frame_id: Arc<AtomicUsize>,
     enabled: bool, // AtomicBool?
-    width: usize,
-    height: usize,
+    // (width, height), which can change if anyone reconfigures the camera
+    size: Arc<RwLock<(usize, usize)>>,
 }
 
 #[cfg(target_os = "linux")]
@@ -211,15 +250,23 @@ impl<F: frame::PixelFormat> Camera<F> {
             .arg("--quiet")
             .output()?;
    
-        let cam_proc = if res.status.success() {
+        // Formats the camera can't make itself are converted from one it can, once connected.
+        let launch_format = |format: vistream_protocol::camera::PixelFormat| {
+            if format.is_camera_format() { format } else { vistream_protocol::camera::PixelFormat::RGB }
+        };
+        let mut cam_proc = if res.status.success() {
             let mut cmd = Command::new(&server_exe);
             cmd.arg("launch");
             cmd.arg("--format");
-            cmd.arg(std::str::from_utf8(&F::proto_format().fourcc()).unwrap());
+            cmd.arg(launch_format(F::proto_format()).to_string());
             if let Some(count) = cfg.buffer_count {
                 cmd.arg("--buffer_count");
                 cmd.arg(count.to_string());
             }
+            if let Some(framerate) = cfg.framerate {
+                cmd.arg("--framerate");
+                cmd.arg(framerate.to_string());
+            }
             if let Some(width) = cfg.width {
                 cmd.arg("--width");
                 cmd.arg(width.to_string());
@@ -228,6 +275,10 @@ impl<F: frame::PixelFormat> Camera<F> {
                 cmd.arg("--height");
                 cmd.arg(height.to_string());
             }
+            for (format, width, height) in &cfg.extra_channels {
+                cmd.arg("--channel");
+                cmd.arg(format!("{}x{}:{}", width, height, launch_format(*format)));
+            }
             cmd.arg(&true_name);
             Some(cmd.spawn()?)
             // Should really do something here to check for success, since there's always a
@@ -257,49 +308,124 @@ impl<F: frame::PixelFormat> Camera<F> {
                     return Err(Error::Timeout);
                 }
             }
+            // A server that's already quit isn't going to start listening.
+            if let (None, Some(proc)) = (&source, cam_proc.as_mut()) {
+                if let Some(status) = proc.try_wait()? {
+                    return Err(Error::Server(format!("camera server exited before it could be reached ({})", status)));
+                }
+            }
         }
         // let mut source = UnixStream::connect_addr(&addr)?;
         let mut source = source.unwrap();
         // println!("connection established");
 
+        // if the camera process is a child, it has to be killed and reaped before bailing
+        let bail = |cam_proc: Option<std::process::Child>, err: Error| -> Result<Camera<F>> {
+            if let Some(mut proc) = cam_proc {
+                proc.kill().expect(&format!("camera process couldn't be killed ({})", proc.id()));
+                proc.wait().expect(&format!("camera process couldn't be waited ({})", proc.id()));
+            }
+            Err(err)
+        };
+
+        source.set_read_timeout(Some(std::time::Duration::from_secs(2)))?;
+        let hello = Hello::new(vec![F::proto_format()], vec![Encoding::Raw, Encoding::Jpeg]);
+        let server = match hello.exchange(&mut source) {
+            Ok(server) => server,
+            Err(e) => return bail(cam_proc, e.into()),
+        };
+        if !server.formats.contains(&F::proto_format()) {
+            return bail(cam_proc, Error::IncompatibleFormat);
+        }
+        source.set_read_timeout(None)?;
+
         //  TODO make the Resizer
         // - image size - set up resizer if necessary
 
-        source.write(&[ClientMessage::Status.id()])?;
-        source.flush()?;
-        let status = Status::deserialize(&mut Deserializer::new(&mut source))?;
+        let command = match cfg.channel {
+            0 => camera::Command::Status,
+            channel => camera::Command::Select { channel },
+        };
+        // The answer is a status, or the server's reason for not sending one.
+        let read_status = |source: &mut UnixStream| -> Result<std::result::Result<Status, String>> {
+            loop {
+                match Message::read_from(source)? {
+                    Message::Status(status) => return Ok(Ok(status)),
+                    Message::Error(msg) => return Ok(Err(msg)),
+                    // other clients of the same camera may have it started already
+                    _ => continue,
+                }
+            }
+        };
+        Message::Command(command).write_to(&mut source)?;
+        let mut status = match read_status(&mut source)? {
+            Ok(status) => status,
+            Err(msg) => return bail(cam_proc, Error::Server(msg)),
+        };
+        // If the camera's making something else, the server can convert it for us.
+        if status.format != F::proto_format() {
+            Message::Command(camera::Command::SetFormat { format: F::proto_format() }).write_to(&mut source)?;
+            status = match read_status(&mut source)? {
+                Ok(status) => status,
+                Err(_) => return bail(cam_proc, Error::IncompatibleFormat),
+            };
+        }
 
         println!("{:?}", F::proto_format());
         println!("{:?}", status);
 
         // if request fails or has wrong format, die
-        // if camera process is a child upon death, kill and reap it before death
         if status.format != F::proto_format() {
-            if let Some(mut proc) = cam_proc {
-                proc.kill().expect(&format!("camera process couldn't be killed ({})", proc.id()));
-                proc.wait().expect(&format!("camera process couldn't be waited ({})", proc.id()));
-            }
-            return Err(Error::IncompatibleFormat);
+            return bail(cam_proc, Error::IncompatibleFormat);
         }
 
         // At this point, we have a camera process, and we're able to talk with it.
         // Note, these sizes are not necessarily the same as the the ones requested.
         // Cropping can happen at a later step
-        let width = status.width;
-        let height = status.height;
+        let size = Arc::new(RwLock::new((status.width, status.height)));
 
         let control = source.try_clone()?;
 
         let last_frame = Arc::new(RwLock::new(None));
         let last_frame_id = Arc::new(AtomicUsize::new(0));
 
+        let (reply_tx, replies) = mpsc::channel();
+
         let worker_frame = last_frame.clone();
         let worker_frame_id = last_frame_id.clone();
+        let worker_size = size.clone();
         let frame_worker = Worker::spawn(move |kill_flag: Arc<AtomicBool>| {
-            let mut deserializer = Deserializer::new(&mut source);
-
             while !kill_flag.load(Ordering::Acquire) {
-                let frame_msg = camera::Frame::deserialize(&mut deserializer)?;
+                let msg = match Message::read_from(&mut source) {
+                    Ok(msg) => msg,
+                    // The whole message was skipped over, so the stream is still in step. It
+                    // might have been a reply someone's waiting on, so they hear about it.
+                    Err(e) if e.is_recoverable() => {
+                        let _ = reply_tx.send(Message::Error(e.to_string()));
+                        continue;
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                let frame_msg = match msg {
+                    Message::Frame(frame) => frame,
+                    // Statuses only show up on their own after a reconfigure, which could
+                    // have been anyone's doing.
+                    Message::Status(status) => {
+                        if status.format != F::proto_format() {
+                            return Err(Error::IncompatibleFormat);
+                        }
+                        *worker_size.write().unwrap() = (status.width, status.height);
+                        // in case someone asked for it
+                        let _ = reply_tx.send(Message::Status(status));
+                        continue;
+                    }
+                    reply => {
+                        // nobody waiting on it is fine too
+                        let _ = reply_tx.send(reply);
+                        continue;
+                    }
+                };
+                let (width, height) = *worker_size.read().unwrap();
                 if frame_msg.width as usize != width || frame_msg.height as usize != height {
                     // shouldn't ever happen, but you never know.
                     return Err(Error::FrameData);
@@ -316,14 +442,107 @@ impl<F: frame::PixelFormat> Camera<F> {
         Ok(Camera {
             name: true_name.to_string(),
             control,
+            replies,
             frame_worker,
             last_frame: last_frame,
             last_frame_id: last_frame_id,
             enabled: false,
-            width,
-            height,
+            size,
         })
     }
+
+    /// The size of the camera's frames, which can change if anyone reconfigures it.
+    pub fn size(&self) -> (usize, usize) {
+        *self.size.read().unwrap()
+    }
+
+    /// Restarts the camera at (about) a new size, which, like at launch, is only a request.
+    /// This is for everyone using the camera's channel, and they all switch over. The camera
+    /// keeps producing the same format, since the server converts it for whoever needs that.
+    pub fn reconfigure(&mut self, width: Option<u32>, height: Option<u32>) -> Result<(usize, usize)> {
+        // The new status comes in before the answer does, so the size is already updated.
+        self.request(camera::Command::Reconfigure { format: None, width, height })?;
+        Ok(self.size())
+    }
+
+    /// Frames per second the camera is actually running at, which can be less than asked
+    /// for. `None` if the server doesn't know yet.
+    pub fn framerate(&mut self) -> Result<Option<f64>> {
+        match self.request(camera::Command::Status)? {
+            Message::Status(status) => Ok(status.framerate),
+            _ => Err(Error::FrameData),
+        }
+    }
+
+    /// Fixes the exposure time, turning auto exposure off, or turns it back on with `None`.
+    pub fn set_exposure(&mut self, exposure: Option<Duration>) -> Result<()> {
+        match exposure {
+            Some(exposure) => {
+                self.set_control("AeEnable", ControlValue::Bool(false))?;
+                self.set_control("ExposureTime", ControlValue::Int(exposure.as_micros() as i64))
+            }
+            None => self.set_control("AeEnable", ControlValue::Bool(true)),
+        }
+    }
+
+    /// Sets the analogue gain, where 1.0 is none. Mostly useful with a fixed exposure.
+    pub fn set_gain(&mut self, gain: f32) -> Result<()> {
+        self.set_control("AnalogueGain", ControlValue::Float(gain))
+    }
+
+    /// Turns auto white balance on or off.
+    pub fn set_awb(&mut self, enabled: bool) -> Result<()> {
+        self.set_control("AwbEnable", ControlValue::Bool(enabled))
+    }
+
+    /// Fixes the lens position, in dioptres (1 / distance in meters, so 0.0 is infinity), or
+    /// goes back to continuous autofocus with `None`.
+    pub fn set_focus(&mut self, dioptres: Option<f32>) -> Result<()> {
+        // AfMode: 0 is manual, 2 is continuous
+        match dioptres {
+            Some(dioptres) => {
+                self.set_control("AfMode", ControlValue::Int(0))?;
+                self.set_control("LensPosition", ControlValue::Float(dioptres))
+            }
+            None => self.set_control("AfMode", ControlValue::Int(2)),
+        }
+    }
+
+    /// Sets any control, by its libcamera name. Controls belong to the camera, so this
+    /// changes things for everyone else using it too.
+    pub fn set_control(&mut self, name: &str, value: ControlValue) -> Result<()> {
+        self.request(camera::Command::SetControl { name: name.to_string(), value })?;
+        Ok(())
+    }
+
+    /// The controls the camera takes, and their ranges where the server knows them.
+    pub fn controls(&mut self) -> Result<Vec<ControlInfo>> {
+        match self.request(camera::Command::ListControls)? {
+            Message::Controls(controls) => Ok(controls),
+            _ => Err(Error::FrameData),
+        }
+    }
+
+    /// Sends a command and waits for the answer.
+    fn request(&mut self, command: camera::Command) -> Result<Message> {
+        // Statuses also go out to everyone when the camera is reconfigured, so they only count
+        // as the answer when one was asked for.
+        let wants_status = command == camera::Command::Status;
+        // anything still here is the answer to a request that timed out
+        while self.replies.try_recv().is_ok() {}
+        Message::Command(command).write_to(&mut self.control)?;
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        loop {
+            match self.replies.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(Message::Status(_)) if !wants_status => continue,
+                Ok(Message::Error(msg)) => return Err(Error::Server(msg)),
+                Ok(reply) => return Ok(reply),
+                Err(RecvTimeoutError::Timeout) => return Err(Error::Timeout),
+                // the worker's gone, and get_frame has the reason why
+                Err(RecvTimeoutError::Disconnected) => return Err(Error::CorruptSource),
+            }
+        }
+    }
 }
 
 
@@ -335,8 +554,7 @@ impl<F: frame::PixelFormat> FrameSource<F> for Camera<F> {
         if self.frame_worker.is_finished() {
             if self.frame_worker.is_joinable() {
                 // ignore the error, since something has already gone wrong
-                let _ = self.control.write(&[ClientMessage::Disconnect.id()]);
-                self.control.flush()?;
+                let _ = Message::Command(camera::Command::Disconnect).write_to(&mut self.control);
                 return match self.frame_worker.join() {
                     Some(e) => Err(e),
                     None => Ok(None),
@@ -356,8 +574,7 @@ impl<F: frame::PixelFormat> FrameSource<F> for Camera<F> {
                 // Most of the code below makes little to no sense in this context, but is just 
                 // checking all the boxes, just in case I'm stupid.
                 self.frame_worker.kill();
-                let _ = self.control.write(&[ClientMessage::Disconnect.id()]);
-                self.control.flush()?;
+                let _ = Message::Command(camera::Command::Disconnect).write_to(&mut self.control);
                 match self.frame_worker.join() {
                     Some(e) => Err(e),
                     None => Ok(None),
@@ -367,14 +584,12 @@ impl<F: frame::PixelFormat> FrameSource<F> for Camera<F> {
     }
 
     fn start(&mut self) -> Result<()> {
-        self.control.write(&[ClientMessage::Start.id()])?;
-        self.control.flush()?;
+        Message::Command(camera::Command::Start).write_to(&mut self.control)?;
         Ok(())
     }
 
     fn stop(&mut self) -> Result<()> {
-        self.control.write(&[ClientMessage::Stop.id()])?;
-        self.control.flush()?;
+        Message::Command(camera::Command::Stop).write_to(&mut self.control)?;
         Ok(())
     }
 
@@ -388,8 +603,7 @@ impl<F: frame::PixelFormat> Drop for Camera<F> {
     fn drop(&mut self) {
         if !self.frame_worker.is_finished() {
             // ignore any write errors. We're in cleanup mode.
-            let _ = self.control.write(&[ClientMessage::Disconnect.id()]);
-            let _ = self.control.flush(); 
+            let _ = Message::Command(camera::Command::Disconnect).write_to(&mut self.control);
             self.frame_worker.kill();
             self.frame_worker.join();
         } else {
@@ -452,6 +666,10 @@ impl<F: frame::PixelFormat, S: FrameSource<F>> FrameSource<F> for FrameSequencer
     fn last_frame_id(&self) -> usize {
         self.source.last_frame_id()
     }
+
+    fn encoder_quality(&self) -> Option<u8> {
+        self.source.encoder_quality()
+    }
 }
 
 use std::time::{Instant, Duration};
@@ -508,4 +726,8 @@ impl<F: frame::PixelFormat, S: FrameSource<F>> FrameSource<F> for FrameRateLimit
     fn last_frame_id(&self) -> usize {
         self.last_id
     }
+
+    fn encoder_quality(&self) -> Option<u8> {
+        self.source.encoder_quality()
+    }
 }
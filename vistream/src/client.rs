@@ -1,43 +1,72 @@
 use crate::camera::{Worker, FrameSource};
-use crate::frame::{Frame, MJPG};
+use crate::frame::{Frame, PixelFormat, MJPG};
 use crate::error::{Result, Error};
-use vistream_protocol::stream::{ClientMessage, Frame as ProtoFrame, LocationData};
+use crate::encode::Encodable;
+use vistream_protocol::stream::{ClientMessage, Frame as ProtoFrame, LocationData, Encoding, Status};
+use vistream_protocol::hello::Hello;
 use std::net::{TcpStream, SocketAddr};
-use std::io::{Write, BufReader};
+use std::io::{self, Write, Read};
+use std::time::Duration;
 
-use std::sync::{Arc, RwLock};
-use std::sync::atomic::{Ordering, AtomicUsize};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::sync::atomic::{Ordering, AtomicBool, AtomicUsize};
 
 use rmp_serde::decode::{Deserializer};
 use serde::Deserialize;
 
-pub struct FrameClient {
+/// Receives frames from a `FrameStream`. The stream has to be sending frames in `F`, or the
+/// first frame to arrive fails with `Error::IncompatibleFormat`. Frames from older servers
+/// don't say what format they're in, and are taken as-is. Frames are handed out as they're
+/// received, so they also have to be in `F`'s native encoding. Anything else, like a PNG
+/// stream, fails with `Error::UnsupportedEncoding`.
+pub struct FrameClient<F: PixelFormat = MJPG> {
     worker: Worker,
     control: TcpStream,
-    last_frame: Arc<RwLock<Option<Arc<Frame<MJPG>>>>>,
+    last_frame: Arc<RwLock<Option<Arc<Frame<F>>>>>,
     last_frame_id: Arc<AtomicUsize>,
+    // encoding and keyframe flag of the last frame
+    last_meta: Arc<RwLock<(Encoding, bool)>>,
 }
 
-impl FrameClient {
-    pub fn connect(addr: SocketAddr) -> Result<FrameClient> {
-        let socket = TcpStream::connect(addr)?;
+impl<F: Encodable + Send + Sync + 'static> FrameClient<F> {
+    pub fn connect(addr: SocketAddr) -> Result<FrameClient<F>> {
+        let mut socket = TcpStream::connect(addr)?;
         // Dear future self: If something is breaking in the FrameClient, it's probably because of
         // this line. Yes, that means you need to actually improve your socket handling.
         let _ = socket.set_read_timeout(Some(std::time::Duration::from_secs(1)));
+        let format = F::try_proto_format();
+        let server = Hello::new(format.into_iter().collect(), vec![F::native_encoding()]).exchange(&mut socket)?;
+        // Formats without a protocol equivalent (H264) can only match each other.
+        let compatible = match format {
+            Some(format) => server.formats.contains(&format),
+            None => server.formats.is_empty(),
+        };
+        if !compatible {
+            return Err(Error::IncompatibleFormat);
+        }
         let control = socket.try_clone()?;
 
         let last_frame = Arc::new(RwLock::new(None));
         let last_frame_id = Arc::new(AtomicUsize::new(0));
+        let last_meta = Arc::new(RwLock::new((Encoding::Jpeg, true)));
         let worker_frame = last_frame.clone();
         let worker_frame_id = last_frame_id.clone();
+        let worker_meta = last_meta.clone();
         let worker = Worker::spawn(move |kill_flag| {
             let mut socket = socket;
             let mut deserializer = Deserializer::new(&mut socket);
             
             while !kill_flag.load(Ordering::Acquire) {
                 let proto_frame = ProtoFrame::deserialize(&mut deserializer)?;
+                if proto_frame.format.is_some_and(|format| Some(format) != F::try_proto_format()) {
+                    return Err(Error::IncompatibleFormat);
+                }
+                if proto_frame.encoding != F::native_encoding() {
+                    return Err(Error::UnsupportedEncoding(proto_frame.encoding));
+                }
                 let data = proto_frame.data;
                 let frame = Frame::new(data, proto_frame.width as usize, proto_frame.height as usize);
+                *worker_meta.write().unwrap() = (proto_frame.encoding, proto_frame.keyframe);
                 *worker_frame.write().unwrap() = Some(Arc::new(frame));
                 worker_frame_id.fetch_add(1, Ordering::AcqRel);
             }
@@ -50,19 +79,32 @@ impl FrameClient {
             control,
             last_frame,
             last_frame_id,
+            last_meta,
         })
     }
+
+    /// How the data of the most recent frame is encoded, which is always `F`'s native
+    /// encoding.
+    pub fn encoding(&self) -> Encoding {
+        self.last_meta.read().map(|m| m.0).unwrap_or_default()
+    }
+
+    /// Whether the most recent frame can be decoded on its own. Always true for anything but
+    /// H264, which needs to start decoding (or forwarding) from a keyframe.
+    pub fn is_keyframe(&self) -> bool {
+        self.last_meta.read().map(|m| m.1).unwrap_or(true)
+    }
 }
 
-impl Drop for FrameClient {
+impl<F: PixelFormat> Drop for FrameClient<F> {
     fn drop(&mut self) {
         self.worker.join();
         let _ = self.control.shutdown(std::net::Shutdown::Both);
     }
 }
 
-impl FrameSource<MJPG> for FrameClient {
-    fn get_frame(&mut self) -> Result<Option<Arc<Frame<MJPG>>>> {
+impl<F: PixelFormat> FrameSource<F> for FrameClient<F> {
+    fn get_frame(&mut self) -> Result<Option<Arc<Frame<F>>>> {
         // If the frame_worker has stopped for any reason, return nothing,
         // except for the first time, where it returns the error causing it.
         if self.worker.is_finished() {
@@ -116,64 +158,164 @@ impl FrameSource<MJPG> for FrameClient {
     }
 }
 
-#[allow(dead_code)]
+// (id of the latest result, latest result, whether the worker is done), with the condvar
+// signalled on every new result and once more when the worker stops
+type LatestLocations = Arc<(Mutex<(usize, Option<Vec<LocationData>>, bool)>, Condvar)>;
+
+/// Receives locations from a `LocateStream`.
 pub struct LocateClient {
     worker: Worker,
     control: TcpStream,
-    last_data: Arc<RwLock<Option<LocationData>>>,
-    last_data_id: Arc<AtomicUsize>,
+    last_data: LatestLocations,
+    last_status: Arc<RwLock<Option<Status>>>,
 }
 
-#[allow(dead_code)]
 impl LocateClient {
     pub fn connect(addr: SocketAddr) -> Result<LocateClient> {
-        let socket = TcpStream::connect(addr)?;
-        let _ = socket.set_read_timeout(Some(std::time::Duration::from_secs(1)));
+        let mut socket = TcpStream::connect(addr)?;
+        socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+        Hello::new(Vec::new(), Vec::new()).exchange(&mut socket)?;
+        // Only so the worker notices when it's being stopped.
+        socket.set_read_timeout(Some(Duration::from_millis(100)))?;
         let control = socket.try_clone()?;
 
-        let last_data = Arc::new(RwLock::new(None));
-        let last_data_id = Arc::new(AtomicUsize::new(0));
+        let last_data = Arc::new((Mutex::new((0, None, false)), Condvar::new()));
+        let last_status = Arc::new(RwLock::new(None));
         let worker_data = last_data.clone();
-        let worker_data_id = last_data_id.clone();
-        
+        let worker_status = last_status.clone();
+
         let worker = Worker::spawn(move |kill_flag| {
-            let socket = serde_json::de::IoRead::new(BufReader::new(socket));
-            let mut deserializer = serde_json::Deserializer::new(socket);
-            
-            while !kill_flag.load(Ordering::Acquire) {
-                let loc_data = LocationData::deserialize(&mut deserializer).unwrap();
-                *worker_data.write().unwrap() = Some(loc_data);
-                worker_data_id.fetch_add(1, Ordering::AcqRel);
+            let mut socket = socket;
+            let res = (|| -> Result<()> {
+                // Each message is [kind][u32 BE length][JSON], see `stream::make_response`.
+                let mut header = [0u8; 5];
+                while read_full(&mut socket, &mut header, &kill_flag)? {
+                    let len = u32::from_be_bytes(header[1..5].try_into().unwrap()) as usize;
+                    let mut body = vec![0u8; len];
+                    if !read_full(&mut socket, &mut body, &kill_flag)? {
+                        break;
+                    }
+                    match ClientMessage::from_id(header[0]) {
+                        Some(ClientMessage::Start) => {
+                            let locs: Vec<LocationData> = serde_json::from_slice(&body).map_err(|_| Error::FrameData)?;
+                            let (data, updated) = &*worker_data;
+                            let mut guard = data.lock().unwrap();
+                            *guard = (guard.0 + 1, Some(locs), false);
+                            updated.notify_all();
+                        }
+                        Some(ClientMessage::Status) => {
+                            let status: Status = serde_json::from_slice(&body).map_err(|_| Error::FrameData)?;
+                            *worker_status.write().unwrap() = Some(status);
+                        }
+                        _ => return Err(Error::FrameData),
+                    }
+                }
+                Ok(())
+            })();
+            // wake up anyone waiting, so they see that the worker stopped
+            let (data, updated) = &*worker_data;
+            if let Ok(mut guard) = data.lock() {
+                guard.2 = true;
             }
-
-            Err(Error::Unknown)
+            updated.notify_all();
+            res
         });
 
         Ok(LocateClient {
             worker,
             control,
             last_data,
-            last_data_id,
+            last_status,
         })
     }
-    
+
     pub fn start(&mut self) -> Result<()> {
-        self.control.write(&[ClientMessage::Start.id()])?;
-        self.control.flush()?;
-        Ok(())
+        self.send(ClientMessage::Start)
     }
 
     pub fn stop(&mut self) -> Result<()> {
-        self.control.write(&[ClientMessage::Stop.id()])?;
-        self.control.flush()?;
-        Ok(())
+        self.send(ClientMessage::Stop)
     }
 
+    /// Asks the server for its status. The reply shows up in `status` once it arrives.
+    pub fn request_status(&mut self) -> Result<()> {
+        self.send(ClientMessage::Status)
+    }
+
+    /// The most recent status reply, if there's been one.
+    pub fn status(&self) -> Option<Status> {
+        self.last_status.read().ok()?.clone()
+    }
+
+    /// The most recent locations, if any have arrived.
+    pub fn latest(&self) -> Option<Vec<LocationData>> {
+        self.last_data.0.lock().ok()?.1.clone()
+    }
+
+    /// Counts up with every result received.
     pub fn last_data_id(&self) -> usize {
-        self.last_data_id.load(Ordering::Acquire)
+        self.last_data.0.lock().map(|guard| guard.0).unwrap_or(0)
     }
-}
 
+    /// Waits up to `timeout` for a result newer than whatever was latest when this was
+    /// called, returning `None` if there wasn't one.
+    ///
+    /// Like `FrameClient::get_frame`, if the connection has failed, this returns the error
+    /// that caused it the first time, and `None` from then on.
+    pub fn wait_update(&mut self, timeout: Duration) -> Result<Option<Vec<LocationData>>> {
+        let (data, updated) = &*self.last_data;
+        let guard = data.lock().map_err(|_| Error::Unknown)?;
+        let last_id = guard.0;
+        let (guard, _) = updated
+            .wait_timeout_while(guard, timeout, |(id, _, closed)| {
+                *id == last_id && !*closed
+            })
+            .map_err(|_| Error::Unknown)?;
+        if guard.0 != last_id {
+            return Ok(guard.1.clone());
+        }
+        let closed = guard.2;
+        drop(guard);
+
+        // The worker might not quite be finished yet, but it's on its way out, so joining
+        // won't take long.
+        if closed && self.worker.is_joinable() {
+            if let Some(e) = self.worker.join() {
+                return Err(e);
+            }
+        }
+        Ok(None)
+    }
 
+    fn send(&mut self, msg: ClientMessage) -> Result<()> {
+        self.control.write_all(&[msg.id()])?;
+        self.control.flush()?;
+        Ok(())
+    }
+}
 
+impl Drop for LocateClient {
+    fn drop(&mut self) {
+        let _ = self.send(ClientMessage::Disconnect);
+        self.worker.join();
+        let _ = self.control.shutdown(std::net::Shutdown::Both);
+    }
+}
 
+/// Fills `buf`, riding out read timeouts until `kill_flag` is set. Returns false if it was
+/// set before `buf` was filled.
+fn read_full(socket: &mut TcpStream, buf: &mut [u8], kill_flag: &AtomicBool) -> Result<bool> {
+    let mut read = 0;
+    while read < buf.len() {
+        if kill_flag.load(Ordering::Acquire) {
+            return Ok(false);
+        }
+        match socket.read(&mut buf[read..]) {
+            Ok(0) => return Err(Error::Server("connection closed".into())),
+            Ok(n) => read += n,
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(true)
+}
@@ -0,0 +1,486 @@
+use crate::frame::{Frame, Luma, Pixelate};
+use vistream_protocol::stream::LocationData;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Point {
+    pub x: f64,
+    pub y: f64,
+}
+
+impl Point {
+    pub fn new(x: f64, y: f64) -> Point {
+        Point { x, y }
+    }
+
+    fn sub(&self, other: &Point) -> Point {
+        Point::new(self.x - other.x, self.y - other.y)
+    }
+
+    fn cross(&self, other: &Point) -> f64 {
+        self.x * other.y - self.y * other.x
+    }
+
+    fn dot(&self, other: &Point) -> f64 {
+        self.x * other.x + self.y * other.y
+    }
+
+    fn dist(&self, other: &Point) -> f64 {
+        (self.x - other.x).hypot(self.y - other.y)
+    }
+}
+
+/// A single traced border. Outer borders wrap a connected region of foreground pixels,
+/// holes wrap a region of background pixels enclosed by foreground.
+#[derive(Clone, Debug)]
+pub struct Contour {
+    points: Vec<Point>,
+    hole: bool,
+    parent: Option<usize>,
+}
+
+impl Contour {
+    pub fn points(&self) -> &[Point] {
+        &self.points
+    }
+
+    pub fn is_hole(&self) -> bool {
+        self.hole
+    }
+
+    /// Index of the enclosing contour in the list returned by `find_contours`, if any.
+    pub fn parent(&self) -> Option<usize> {
+        self.parent
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    pub fn area(&self) -> f64 {
+        self.moments().m00
+    }
+
+    pub fn perimeter(&self) -> f64 {
+        perimeter(&self.points, true)
+    }
+
+    /// Axis-aligned bounds as `(min, max)` corners.
+    pub fn bounds(&self) -> (Point, Point) {
+        let mut min = Point::new(f64::MAX, f64::MAX);
+        let mut max = Point::new(f64::MIN, f64::MIN);
+        for p in self.points.iter() {
+            min.x = min.x.min(p.x);
+            min.y = min.y.min(p.y);
+            max.x = max.x.max(p.x);
+            max.y = max.y.max(p.y);
+        }
+        (min, max)
+    }
+
+    pub fn approx_poly(&self, epsilon: f64) -> Vec<Point> {
+        approx_poly(&self.points, epsilon, true)
+    }
+
+    pub fn convex_hull(&self) -> Vec<Point> {
+        convex_hull(&self.points)
+    }
+
+    pub fn min_area_rect(&self) -> RotatedRect {
+        min_area_rect(&self.points)
+    }
+
+    pub fn moments(&self) -> Moments {
+        Moments::of_polygon(&self.points)
+    }
+}
+
+impl From<&Contour> for LocationData {
+    fn from(contour: &Contour) -> LocationData {
+        LocationData::from(&contour.min_area_rect())
+    }
+}
+
+/// Traces every border in a binary frame using Suzuki-Abe border following.
+/// Any nonzero pixel is treated as foreground.
+pub fn find_contours(frame: &Frame<Luma>) -> Vec<Contour> {
+    let width = frame.width();
+    let height = frame.height();
+    let bytes = frame.bytes();
+
+    // Padded by one pixel on every side, so border following never needs bounds checks.
+    let stride = width + 2;
+    let mut labels = vec![0i32; stride * (height + 2)];
+    for y in 0..height {
+        for x in 0..width {
+            if bytes[y * width + x] != 0 {
+                labels[(y + 1) * stride + x + 1] = 1;
+            }
+        }
+    }
+
+    let mut contours: Vec<Contour> = Vec::new();
+    // Border number 1 is the frame itself, so contour n lives at label n + 2.
+    let mut nbd = 1i32;
+
+    for y in 1..=height {
+        let mut lnbd = 1i32;
+        for x in 1..=width {
+            let here = y * stride + x;
+            let value = labels[here];
+
+            let start = if value == 1 && labels[here - 1] == 0 {
+                Some((false, (y, x - 1)))
+            } else if value >= 1 && labels[here + 1] == 0 {
+                if value > 1 {
+                    lnbd = value;
+                }
+                Some((true, (y, x + 1)))
+            } else {
+                None
+            };
+
+            if let Some((hole, from)) = start {
+                nbd += 1;
+                let neighbor = (lnbd > 1).then(|| (lnbd - 2) as usize);
+                let parent = match neighbor {
+                    Some(n) if contours[n].hole == hole => contours[n].parent,
+                    other => other,
+                };
+                let points = follow_border(&mut labels, stride, (y, x), from, nbd);
+                contours.push(Contour { points, hole, parent });
+            }
+
+            let value = labels[here];
+            if value != 0 && value != 1 {
+                lnbd = value.abs();
+            }
+        }
+    }
+
+    contours
+}
+
+// Clockwise in image coordinates (y grows downward), starting from east.
+const NEIGHBORS: [(isize, isize); 8] = [
+    (0, 1), (1, 1), (1, 0), (1, -1), (0, -1), (-1, -1), (-1, 0), (-1, 1),
+];
+
+fn neighbor_index(center: (usize, usize), other: (usize, usize)) -> usize {
+    let dy = other.0 as isize - center.0 as isize;
+    let dx = other.1 as isize - center.1 as isize;
+    NEIGHBORS.iter().position(|&d| d == (dy, dx)).unwrap()
+}
+
+fn offset(p: (usize, usize), dir: usize) -> (usize, usize) {
+    let (dy, dx) = NEIGHBORS[dir];
+    ((p.0 as isize + dy) as usize, (p.1 as isize + dx) as usize)
+}
+
+fn follow_border(labels: &mut [i32], stride: usize, start: (usize, usize), from: (usize, usize), nbd: i32) -> Vec<Point> {
+    let at = |p: (usize, usize)| p.0 * stride + p.1;
+    let to_point = |p: (usize, usize)| Point::new((p.1 - 1) as f64, (p.0 - 1) as f64);
+
+    // Look clockwise around the start pixel for the first foreground neighbor.
+    let first = neighbor_index(start, from);
+    let found = (0..8)
+        .map(|k| offset(start, (first + k) % 8))
+        .find(|&p| labels[at(p)] != 0);
+
+    let Some(p1) = found else {
+        // isolated pixel
+        labels[at(start)] = -nbd;
+        return vec![to_point(start)];
+    };
+
+    let mut points = Vec::new();
+    let mut p2 = p1;
+    let mut p3 = start;
+    loop {
+        // Counterclockwise around p3, starting just past p2.
+        let first = neighbor_index(p3, p2);
+        let mut east_is_background = false;
+        let mut p4 = p3;
+        for k in 1..=8 {
+            let dir = (first + 8 - k) % 8;
+            let p = offset(p3, dir);
+            if labels[at(p)] != 0 {
+                p4 = p;
+                break;
+            }
+            if dir == 0 {
+                east_is_background = true;
+            }
+        }
+
+        if east_is_background {
+            labels[at(p3)] = -nbd;
+        } else if labels[at(p3)] == 1 {
+            labels[at(p3)] = nbd;
+        }
+        points.push(to_point(p3));
+
+        if p4 == start && p3 == p1 {
+            break;
+        }
+        p2 = p3;
+        p3 = p4;
+    }
+
+    points
+}
+
+pub fn perimeter(points: &[Point], closed: bool) -> f64 {
+    let mut total: f64 = points.windows(2).map(|w| w[0].dist(&w[1])).sum();
+    if closed && points.len() > 1 {
+        total += points[points.len() - 1].dist(&points[0]);
+    }
+    total
+}
+
+fn line_distance(p: &Point, a: &Point, b: &Point) -> f64 {
+    let ab = b.sub(a);
+    let len = ab.x.hypot(ab.y);
+    if len == 0.0 {
+        return p.dist(a);
+    }
+    ab.cross(&p.sub(a)).abs() / len
+}
+
+fn douglas_peucker(points: &[Point], epsilon: f64, out: &mut Vec<Point>) {
+    // Pushes every kept point except the last one, so chains can be joined end to end.
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    let mut stack = vec![(0, points.len() - 1)];
+    while let Some((first, last)) = stack.pop() {
+        let mut max_dist = 0.0;
+        let mut max_index = first;
+        for i in first + 1..last {
+            let dist = line_distance(&points[i], &points[first], &points[last]);
+            if dist > max_dist {
+                max_dist = dist;
+                max_index = i;
+            }
+        }
+        if max_dist > epsilon {
+            keep[max_index] = true;
+            stack.push((first, max_index));
+            stack.push((max_index, last));
+        }
+    }
+    let last = points.len() - 1;
+    out.extend(points[..last].iter().zip(&keep[..last]).filter(|(_, &k)| k).map(|(p, _)| *p));
+}
+
+/// Douglas-Peucker polygon approximation. `epsilon` is the maximum distance, in pixels,
+/// between the original curve and its approximation.
+pub fn approx_poly(points: &[Point], epsilon: f64, closed: bool) -> Vec<Point> {
+    if points.len() < 3 {
+        return points.to_vec();
+    }
+    let mut out = Vec::new();
+    if closed {
+        // Split the loop at the point farthest from the first one and simplify each half.
+        let far = (1..points.len())
+            .max_by(|&a, &b| points[0].dist(&points[a]).total_cmp(&points[0].dist(&points[b])))
+            .unwrap();
+        let mut second: Vec<Point> = points[far..].to_vec();
+        second.push(points[0]);
+        douglas_peucker(&points[..=far], epsilon, &mut out);
+        douglas_peucker(&second, epsilon, &mut out);
+    } else {
+        douglas_peucker(points, epsilon, &mut out);
+        out.push(points[points.len() - 1]);
+    }
+    out
+}
+
+/// Convex hull by Andrew's monotone chain. The first point is not repeated at the end.
+pub fn convex_hull(points: &[Point]) -> Vec<Point> {
+    let mut sorted = points.to_vec();
+    sorted.sort_by(|a, b| a.x.total_cmp(&b.x).then(a.y.total_cmp(&b.y)));
+    sorted.dedup();
+    if sorted.len() < 3 {
+        return sorted;
+    }
+
+    let turn = |a: &Point, b: &Point, c: &Point| b.sub(a).cross(&c.sub(a));
+    let mut hull: Vec<Point> = Vec::with_capacity(sorted.len() * 2);
+    for p in sorted.iter() {
+        while hull.len() >= 2 && turn(&hull[hull.len() - 2], &hull[hull.len() - 1], p) <= 0.0 {
+            hull.pop();
+        }
+        hull.push(*p);
+    }
+    let lower_len = hull.len() + 1;
+    for p in sorted.iter().rev().skip(1) {
+        while hull.len() >= lower_len && turn(&hull[hull.len() - 2], &hull[hull.len() - 1], p) <= 0.0 {
+            hull.pop();
+        }
+        hull.push(*p);
+    }
+    hull.pop();
+    hull
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RotatedRect {
+    pub center: Point,
+    /// Length of the long side.
+    pub width: f64,
+    /// Length of the short side.
+    pub height: f64,
+    /// Angle of the long side from the x axis, in degrees, within (-90, 90].
+    pub angle: f64,
+}
+
+impl RotatedRect {
+    pub fn area(&self) -> f64 {
+        self.width * self.height
+    }
+
+    pub fn corners(&self) -> [Point; 4] {
+        let (sin, cos) = self.angle.to_radians().sin_cos();
+        let (hw, hh) = (self.width / 2.0, self.height / 2.0);
+        let corner = |u: f64, v: f64| {
+            Point::new(self.center.x + u * cos - v * sin, self.center.y + u * sin + v * cos)
+        };
+        [corner(-hw, -hh), corner(hw, -hh), corner(hw, hh), corner(-hw, hh)]
+    }
+}
+
+impl From<&RotatedRect> for LocationData {
+    fn from(rect: &RotatedRect) -> LocationData {
+        LocationData {
+            x: rect.center.x,
+            y: rect.center.y,
+            width: Some(rect.width),
+            height: Some(rect.height),
+            roll: Some(rect.angle),
+            ..Default::default()
+        }
+    }
+}
+
+/// Folds an undirected angle, in degrees, into (-90, 90].
+pub(crate) fn normalize_angle(mut angle: f64) -> f64 {
+    while angle > 90.0 {
+        angle -= 180.0;
+    }
+    while angle <= -90.0 {
+        angle += 180.0;
+    }
+    angle
+}
+
+/// Minimum-area enclosing rectangle, found with rotating calipers over the convex hull.
+pub fn min_area_rect(points: &[Point]) -> RotatedRect {
+    let hull = convex_hull(points);
+    match hull.len() {
+        0 => return RotatedRect::default(),
+        1 => return RotatedRect { center: hull[0], ..Default::default() },
+        _ => {}
+    }
+
+    let mut best: Option<(f64, RotatedRect)> = None;
+    for i in 0..hull.len() {
+        let edge = hull[(i + 1) % hull.len()].sub(&hull[i]);
+        let len = edge.x.hypot(edge.y);
+        if len == 0.0 {
+            continue;
+        }
+        let u = Point::new(edge.x / len, edge.y / len);
+        let v = Point::new(-u.y, u.x);
+
+        let (mut min_u, mut max_u, mut min_v, mut max_v) = (f64::MAX, f64::MIN, f64::MAX, f64::MIN);
+        for p in hull.iter() {
+            let pu = p.dot(&u);
+            let pv = p.dot(&v);
+            min_u = min_u.min(pu);
+            max_u = max_u.max(pu);
+            min_v = min_v.min(pv);
+            max_v = max_v.max(pv);
+        }
+
+        let area = (max_u - min_u) * (max_v - min_v);
+        if best.as_ref().is_some_and(|(a, _)| *a <= area) {
+            continue;
+        }
+
+        let mid_u = (min_u + max_u) / 2.0;
+        let mid_v = (min_v + max_v) / 2.0;
+        let center = Point::new(mid_u * u.x + mid_v * v.x, mid_u * u.y + mid_v * v.y);
+        let (mut width, mut height) = (max_u - min_u, max_v - min_v);
+        let mut angle = u.y.atan2(u.x).to_degrees();
+        if height > width {
+            std::mem::swap(&mut width, &mut height);
+            angle += 90.0;
+        }
+        let angle = normalize_angle(angle);
+        best = Some((area, RotatedRect { center, width, height, angle }));
+    }
+
+    best.map(|(_, rect)| rect).unwrap_or_default()
+}
+
+/// Spatial and central moments of a polygon, up to second order.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Moments {
+    pub m00: f64,
+    pub m10: f64,
+    pub m01: f64,
+    pub m20: f64,
+    pub m11: f64,
+    pub m02: f64,
+    pub mu20: f64,
+    pub mu11: f64,
+    pub mu02: f64,
+}
+
+impl Moments {
+    pub fn of_polygon(points: &[Point]) -> Moments {
+        let mut m = Moments::default();
+        if points.len() < 3 {
+            return m;
+        }
+        for i in 0..points.len() {
+            let p0 = points[i];
+            let p1 = points[(i + 1) % points.len()];
+            let cross = p0.cross(&p1);
+            m.m00 += cross;
+            m.m10 += (p0.x + p1.x) * cross;
+            m.m01 += (p0.y + p1.y) * cross;
+            m.m20 += (p0.x * p0.x + p0.x * p1.x + p1.x * p1.x) * cross;
+            m.m02 += (p0.y * p0.y + p0.y * p1.y + p1.y * p1.y) * cross;
+            m.m11 += (p0.x * p1.y + 2.0 * p0.x * p0.y + 2.0 * p1.x * p1.y + p1.x * p0.y) * cross;
+        }
+        // Orientation of the traced polygon decides the sign, so normalize it away.
+        let sign = if m.m00 < 0.0 { -1.0 } else { 1.0 };
+        m.m00 *= sign / 2.0;
+        m.m10 *= sign / 6.0;
+        m.m01 *= sign / 6.0;
+        m.m20 *= sign / 12.0;
+        m.m02 *= sign / 12.0;
+        m.m11 *= sign / 24.0;
+
+        if m.m00 != 0.0 {
+            let (cx, cy) = (m.m10 / m.m00, m.m01 / m.m00);
+            m.mu20 = m.m20 - cx * m.m10;
+            m.mu11 = m.m11 - cx * m.m01;
+            m.mu02 = m.m02 - cy * m.m01;
+        }
+        m
+    }
+
+    pub fn centroid(&self) -> Option<Point> {
+        (self.m00 != 0.0).then(|| Point::new(self.m10 / self.m00, self.m01 / self.m00))
+    }
+
+    /// Angle of the principal axis from the x axis, in degrees.
+    pub fn orientation(&self) -> f64 {
+        (0.5 * (2.0 * self.mu11).atan2(self.mu20 - self.mu02)).to_degrees()
+    }
+}
@@ -0,0 +1,259 @@
+use std::sync::Arc;
+use std::marker::PhantomData;
+
+use crate::camera::FrameSource;
+#[cfg(target_os = "linux")]
+use crate::camera::{Camera, CameraConfig};
+use crate::error::{Error, Result};
+use crate::frame::{self, Frame, PixelFormat, Pixelate};
+
+use vistream_protocol::camera::PixelFormat as ProtoPixelFormat;
+
+/// A pixel format that has a runtime equivalent in `protocol::camera::PixelFormat`, and so
+/// can be wrapped up in an `AnyFrame`.
+pub trait DynFormat: PixelFormat + 'static {
+    fn wrap(frame: Arc<Frame<Self>>) -> AnyFrame;
+    fn unwrap(frame: AnyFrame) -> std::result::Result<Arc<Frame<Self>>, AnyFrame>;
+    fn unwrap_ref(frame: &AnyFrame) -> Option<&Arc<Frame<Self>>>;
+}
+
+macro_rules! any_frame {
+    ($($variant:ident => $fmt:ty),* $(,)?) => {
+        /// A frame whose pixel format is only known at runtime. Each variant is named after
+        /// the `protocol::camera::PixelFormat` it corresponds to.
+        #[derive(Clone)]
+        pub enum AnyFrame {
+            $($variant(Arc<Frame<$fmt>>),)*
+        }
+
+        impl AnyFrame {
+            pub fn format(&self) -> ProtoPixelFormat {
+                match self {
+                    $(AnyFrame::$variant(_) => ProtoPixelFormat::$variant,)*
+                }
+            }
+
+            pub fn width(&self) -> usize {
+                match self {
+                    $(AnyFrame::$variant(f) => f.width(),)*
+                }
+            }
+
+            pub fn height(&self) -> usize {
+                match self {
+                    $(AnyFrame::$variant(f) => f.height(),)*
+                }
+            }
+
+            pub fn bytes(&self) -> &[u8] {
+                match self {
+                    $(AnyFrame::$variant(f) => f.bytes(),)*
+                }
+            }
+        }
+
+        $(
+        impl DynFormat for $fmt {
+            fn wrap(frame: Arc<Frame<Self>>) -> AnyFrame {
+                AnyFrame::$variant(frame)
+            }
+
+            fn unwrap(frame: AnyFrame) -> std::result::Result<Arc<Frame<Self>>, AnyFrame> {
+                match frame {
+                    AnyFrame::$variant(f) => Ok(f),
+                    other => Err(other),
+                }
+            }
+
+            fn unwrap_ref(frame: &AnyFrame) -> Option<&Arc<Frame<Self>>> {
+                match frame {
+                    AnyFrame::$variant(f) => Some(f),
+                    _ => None,
+                }
+            }
+        }
+        )*
+
+        #[cfg(target_os = "linux")]
+        impl AnyFrameSource {
+            /// Opens a camera in a format picked at runtime, such as from a config file.
+            pub fn camera(name: &str, format: ProtoPixelFormat, cfg: CameraConfig) -> Result<AnyFrameSource> {
+                match format {
+                    $(ProtoPixelFormat::$variant => Ok(AnyFrameSource::new(Camera::<$fmt>::new(name, cfg)?)),)*
+                }
+            }
+        }
+    }
+}
+
+any_frame! {
+    RGB => frame::RGB,
+    BGR => frame::BGR,
+    RGBA => frame::RGBA,
+    BGRA => frame::BGRA,
+    YUYV => frame::YUYV,
+    MJPEG => frame::MJPG,
+    SRGGB8 => frame::SRGGB8,
+    SRGGB10 => frame::SRGGB10,
+    SRGGB12 => frame::SRGGB12,
+    SRGGB10P => frame::SRGGB10P,
+    SRGGB12P => frame::SRGGB12P,
+    Luma => frame::Luma,
+}
+
+impl AnyFrame {
+    /// Recovers the statically typed frame, failing with `Error::IncompatibleFormat` if
+    /// this frame is in some other format.
+    pub fn downcast<F: DynFormat>(self) -> Result<Arc<Frame<F>>> {
+        F::unwrap(self).map_err(|_| Error::IncompatibleFormat)
+    }
+
+    pub fn downcast_ref<F: DynFormat>(&self) -> Option<&Arc<Frame<F>>> {
+        F::unwrap_ref(self)
+    }
+
+    pub fn is<F: DynFormat>(&self) -> bool {
+        self.format() == F::proto_format()
+    }
+}
+
+impl<F: DynFormat> From<Arc<Frame<F>>> for AnyFrame {
+    fn from(frame: Arc<Frame<F>>) -> AnyFrame {
+        F::wrap(frame)
+    }
+}
+
+impl<F: DynFormat> From<Frame<F>> for AnyFrame {
+    fn from(frame: Frame<F>) -> AnyFrame {
+        F::wrap(Arc::new(frame))
+    }
+}
+
+// Object-safe mirror of FrameSource, with the format erased.
+trait DynSource: Send {
+    fn get_frame(&mut self) -> Result<Option<AnyFrame>>;
+    fn start(&mut self) -> Result<()>;
+    fn stop(&mut self) -> Result<()>;
+    fn last_frame_id(&self) -> usize;
+    fn encoder_quality(&self) -> Option<u8>;
+}
+
+struct Erased<F: DynFormat, S: FrameSource<F>> {
+    source: S,
+    _format: PhantomData<fn() -> F>,
+}
+
+impl<F: DynFormat, S: FrameSource<F> + Send> DynSource for Erased<F, S> {
+    fn get_frame(&mut self) -> Result<Option<AnyFrame>> {
+        Ok(self.source.get_frame()?.map(F::wrap))
+    }
+
+    fn start(&mut self) -> Result<()> {
+        self.source.start()
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.source.stop()
+    }
+
+    fn last_frame_id(&self) -> usize {
+        self.source.last_frame_id()
+    }
+
+    fn encoder_quality(&self) -> Option<u8> {
+        self.source.encoder_quality()
+    }
+}
+
+/// Type-erased wrapper around any `FrameSource<F>`, for pipelines whose format is decided
+/// at runtime. Use `downcast` to get back to a `FrameSource<F>` once the format is known.
+pub struct AnyFrameSource {
+    source: Box<dyn DynSource>,
+    format: ProtoPixelFormat,
+}
+
+impl AnyFrameSource {
+    pub fn new<F, S>(source: S) -> AnyFrameSource
+    where F: DynFormat,
+          S: FrameSource<F> + Send + 'static {
+        AnyFrameSource {
+            source: Box::new(Erased {
+                source,
+                _format: PhantomData,
+            }),
+            format: F::proto_format(),
+        }
+    }
+
+    pub fn format(&self) -> ProtoPixelFormat {
+        self.format
+    }
+
+    pub fn get_frame(&mut self) -> Result<Option<AnyFrame>> {
+        self.source.get_frame()
+    }
+
+    pub fn start(&mut self) -> Result<()> {
+        self.source.start()
+    }
+
+    pub fn stop(&mut self) -> Result<()> {
+        self.source.stop()
+    }
+
+    pub fn last_frame_id(&self) -> usize {
+        self.source.last_frame_id()
+    }
+
+    pub fn encoder_quality(&self) -> Option<u8> {
+        self.source.encoder_quality()
+    }
+
+    /// Turns this back into a statically typed source, failing with
+    /// `Error::IncompatibleFormat` if it produces some other format.
+    pub fn downcast<F: DynFormat>(self) -> Result<Downcast<F>> {
+        if self.format != F::proto_format() {
+            return Err(Error::IncompatibleFormat);
+        }
+        Ok(Downcast {
+            source: self,
+            _format: PhantomData,
+        })
+    }
+}
+
+pub struct Downcast<F: DynFormat> {
+    source: AnyFrameSource,
+    _format: PhantomData<fn() -> F>,
+}
+
+impl<F: DynFormat> Downcast<F> {
+    pub fn into_inner(self) -> AnyFrameSource {
+        self.source
+    }
+}
+
+impl<F: DynFormat> FrameSource<F> for Downcast<F> {
+    fn get_frame(&mut self) -> Result<Option<Arc<Frame<F>>>> {
+        match self.source.get_frame()? {
+            Some(frame) => Ok(Some(frame.downcast()?)),
+            None => Ok(None),
+        }
+    }
+
+    fn start(&mut self) -> Result<()> {
+        self.source.start()
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.source.stop()
+    }
+
+    fn last_frame_id(&self) -> usize {
+        self.source.last_frame_id()
+    }
+
+    fn encoder_quality(&self) -> Option<u8> {
+        self.source.encoder_quality()
+    }
+}
@@ -0,0 +1,164 @@
+//! Encodings for frames sent over a `FrameStream`.
+//!
+//! PNG and QOI are lossless, so they're the ones to use for masks and debug images. They are
+//! behind the `png` and `qoi` features respectively.
+
+#[allow(unused_imports)]
+use crate::frame::{self, Frame, PixelFormat, Pixelate};
+use crate::error::{Error, Result};
+
+pub use vistream_protocol::stream::Encoding;
+
+pub trait Encodable: PixelFormat {
+    /// The encoding of the frame's bytes as they are, with no extra work.
+    fn native_encoding() -> Encoding {
+        Encoding::Raw
+    }
+
+    fn supports(encoding: Encoding) -> bool {
+        encoding == Self::native_encoding()
+    }
+
+    /// Whether `frame` can be decoded without any of the frames before it.
+    fn is_keyframe(_frame: &Frame<Self>) -> bool {
+        true
+    }
+
+    /// Encodes `frame` into `out`, replacing whatever was there.
+    fn encode(frame: &Frame<Self>, encoding: Encoding, out: &mut Vec<u8>) -> Result<()> {
+        if encoding != Self::native_encoding() {
+            return Err(Error::UnsupportedEncoding(encoding));
+        }
+        out.clear();
+        out.extend_from_slice(frame.bytes());
+        Ok(())
+    }
+}
+
+impl Encodable for frame::MJPG {
+    fn native_encoding() -> Encoding {
+        Encoding::Jpeg
+    }
+}
+
+impl Encodable for frame::H264 {
+    fn native_encoding() -> Encoding {
+        Encoding::H264
+    }
+
+    fn is_keyframe(frame: &Frame<Self>) -> bool {
+        // Look for an IDR slice (NAL type 5) after any of the Annex B start codes.
+        frame.bytes().windows(4).any(|w| w[..3] == [0, 0, 1] && w[3] & 0x1f == 5)
+    }
+}
+
+impl Encodable for frame::YUYV {}
+impl Encodable for frame::SRGGB8 {}
+impl Encodable for frame::SRGGB10 {}
+impl Encodable for frame::SRGGB12 {}
+impl Encodable for frame::SRGGB10P {}
+impl Encodable for frame::SRGGB12P {}
+impl Encodable for frame::SRGGB16 {}
+impl<const N: usize> Encodable for frame::Raw<N> {}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Order {
+    Rgb,
+    Bgr,
+    Rgba,
+    Bgra,
+    Gray,
+}
+
+macro_rules! lossless {
+    ($fmt:ty, $order:expr) => {
+        impl Encodable for $fmt {
+            fn supports(encoding: Encoding) -> bool {
+                match encoding {
+                    Encoding::Raw => true,
+                    Encoding::Png => cfg!(feature = "png"),
+                    Encoding::Qoi => cfg!(feature = "qoi"),
+                    Encoding::Jpeg | Encoding::H264 => false,
+                }
+            }
+
+            fn encode(frame: &Frame<Self>, encoding: Encoding, out: &mut Vec<u8>) -> Result<()> {
+                if !frame.is_pixelable() {
+                    return Err(Error::FrameData);
+                }
+                match encoding {
+                    Encoding::Raw => {
+                        out.clear();
+                        out.extend_from_slice(frame.bytes());
+                        Ok(())
+                    }
+                    #[cfg(feature = "png")]
+                    Encoding::Png => encode_png(frame.bytes(), frame.width(), frame.height(), $order, out),
+                    #[cfg(feature = "qoi")]
+                    Encoding::Qoi => encode_qoi(frame.bytes(), frame.width(), frame.height(), $order, out),
+                    other => Err(Error::UnsupportedEncoding(other)),
+                }
+            }
+        }
+    }
+}
+
+lossless!(frame::RGB, Order::Rgb);
+lossless!(frame::BGR, Order::Bgr);
+lossless!(frame::RGBA, Order::Rgba);
+lossless!(frame::BGRA, Order::Bgra);
+lossless!(frame::Luma, Order::Gray);
+
+// Neither PNG nor QOI know about blue-first layouts, so those get swapped on the way in.
+#[allow(dead_code)]
+fn swap_red_blue(pixels: &[u8], channels: usize) -> Vec<u8> {
+    let mut out = pixels.to_vec();
+    for p in out.chunks_exact_mut(channels) {
+        p.swap(0, 2);
+    }
+    out
+}
+
+#[cfg(feature = "png")]
+fn encode_png(pixels: &[u8], width: usize, height: usize, order: Order, out: &mut Vec<u8>) -> Result<()> {
+    use std::borrow::Cow;
+    use png::{BitDepth, ColorType, Compression};
+
+    let (color, data) = match order {
+        Order::Rgb => (ColorType::Rgb, Cow::Borrowed(pixels)),
+        Order::Rgba => (ColorType::Rgba, Cow::Borrowed(pixels)),
+        Order::Bgr => (ColorType::Rgb, Cow::Owned(swap_red_blue(pixels, 3))),
+        Order::Bgra => (ColorType::Rgba, Cow::Owned(swap_red_blue(pixels, 4))),
+        Order::Gray => (ColorType::Grayscale, Cow::Borrowed(pixels)),
+    };
+
+    out.clear();
+    let mut encoder = png::Encoder::new(&mut *out, width as u32, height as u32);
+    encoder.set_color(color);
+    encoder.set_depth(BitDepth::Eight);
+    // Frames are going out live, so speed matters more than size.
+    encoder.set_compression(Compression::Fast);
+    let mut writer = encoder.write_header().map_err(|_| Error::Unknown)?;
+    writer.write_image_data(&data).map_err(|_| Error::Unknown)?;
+    writer.finish().map_err(|_| Error::Unknown)
+}
+
+#[cfg(feature = "qoi")]
+fn encode_qoi(pixels: &[u8], width: usize, height: usize, order: Order, out: &mut Vec<u8>) -> Result<()> {
+    use std::borrow::Cow;
+
+    // QOI only does RGB and RGBA, so gray gets spread across all three channels.
+    let data = match order {
+        Order::Rgb | Order::Rgba => Cow::Borrowed(pixels),
+        Order::Bgr => Cow::Owned(swap_red_blue(pixels, 3)),
+        Order::Bgra => Cow::Owned(swap_red_blue(pixels, 4)),
+        Order::Gray => Cow::Owned(pixels.iter().flat_map(|&l| [l, l, l]).collect()),
+    };
+
+    let encoder = qoi::Encoder::new(&data, width as u32, height as u32).map_err(|_| Error::FrameData)?;
+    out.resize(encoder.required_buf_len(), 0);
+    let size = encoder.encode_to_buf(&mut *out).map_err(|_| Error::Unknown)?;
+    out.truncate(size);
+    Ok(())
+}
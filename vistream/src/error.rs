@@ -15,6 +15,9 @@ pub enum Error {
     #[error("camera connection timeout")]
     Timeout,
 
+    #[error("frame encoding {0:?} is not supported for this format")]
+    UnsupportedEncoding(vistream_protocol::stream::Encoding),
+
     #[error("server error: {0}")]
     Server(String),
 
@@ -24,6 +27,12 @@ pub enum Error {
     #[error(transparent)]
     InEncoding(#[from] rmp_serde::decode::Error),
 
+    #[error(transparent)]
+    Protocol(#[from] vistream_protocol::hello::HandshakeError),
+
+    #[error(transparent)]
+    Message(#[from] vistream_protocol::camera::MessageError),
+
     #[cfg(feature = "ws")]
     #[error(transparent)]
     WebSocket(#[from] tungstenite::Error),
@@ -0,0 +1,316 @@
+//! Replaying frames from disk, for working without a camera.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::camera::FrameSource;
+use crate::error::{Error, Result};
+use crate::frame::{Frame, PixelFormat, MJPG};
+
+/// What to do after the last frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Repeat {
+    Loop,
+    /// Stop producing frames, with `get_frame` returning `None` from then on.
+    Once,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pacing {
+    /// Frames advance with the clock at the given framerate, like a camera would. Frames that
+    /// aren't asked for in time are skipped.
+    RealTime(f64),
+    /// Every call to `get_frame` gets the next frame.
+    Unpaced,
+}
+
+/// Play time for the sources that replay or make up frames. Like a camera, they produce
+/// nothing until they're started, and pick up where they left off if started again after a
+/// stop.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PlayClock {
+    // Play time before the most recent start, and when that was.
+    elapsed: Duration,
+    running_since: Option<Instant>,
+    speed: f64,
+}
+
+impl PlayClock {
+    pub(crate) fn new() -> PlayClock {
+        PlayClock {
+            elapsed: Duration::ZERO,
+            running_since: None,
+            speed: 1.0,
+        }
+    }
+
+    pub(crate) fn start(&mut self) {
+        if self.running_since.is_none() {
+            self.running_since = Some(Instant::now());
+        }
+    }
+
+    pub(crate) fn stop(&mut self) {
+        if let Some(since) = self.running_since.take() {
+            self.elapsed += since.elapsed().mul_f64(self.speed);
+        }
+    }
+
+    /// Play time so far, or `None` while stopped.
+    pub(crate) fn now(&self) -> Option<Duration> {
+        self.running_since.map(|since| self.elapsed + since.elapsed().mul_f64(self.speed))
+    }
+
+    /// How fast play time goes relative to real time, which has to be finite and at least 0.
+    pub(crate) fn set_speed(&mut self, speed: f64) {
+        // Bank the time played so far at the old speed.
+        if let Some(now) = self.now() {
+            self.elapsed = now;
+            self.running_since = Some(Instant::now());
+        }
+        self.speed = speed;
+    }
+
+    /// Goes back to no time played, without stopping or starting.
+    pub(crate) fn reset(&mut self) {
+        self.elapsed = Duration::ZERO;
+        if self.running_since.is_some() {
+            self.running_since = Some(Instant::now());
+        }
+    }
+}
+
+enum Chunk {
+    File(PathBuf),
+    Range {
+        offset: u64,
+        len: usize,
+    },
+}
+
+/// A `FrameSource` that plays back frames from a directory of jpgs, an MJPEG file (jpgs back
+/// to back), or a file of raw frames.
+pub struct FileSource<F: PixelFormat> {
+    // The file that `Chunk::Range`s are in.
+    path: PathBuf,
+    chunks: Vec<Chunk>,
+    // Only known ahead of time for raw frames. jpgs carry their own.
+    dims: Option<(usize, usize)>,
+    repeat: Repeat,
+    pacing: Pacing,
+    clock: PlayClock,
+    // Number of frames into playback of the current frame, counting every loop.
+    position: Option<usize>,
+    current: Option<Arc<Frame<F>>>,
+    _format: PhantomData<F>,
+}
+
+impl FileSource<MJPG> {
+    /// Every `.jpg` or `.jpeg` file in `dir`, in file name order.
+    pub fn directory<P: AsRef<Path>>(dir: P) -> Result<FileSource<MJPG>> {
+        let mut files: Vec<PathBuf> = std::fs::read_dir(dir.as_ref())?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| {
+                path.extension()
+                    .and_then(|ext| ext.to_str())
+                    .is_some_and(|ext| ext.eq_ignore_ascii_case("jpg") || ext.eq_ignore_ascii_case("jpeg"))
+            })
+            .collect();
+        files.sort();
+        let chunks = files.into_iter().map(Chunk::File).collect();
+        FileSource::with_chunks(dir.as_ref().to_owned(), chunks, None)
+    }
+
+    /// A file of jpgs one after another, as recorded from an MJPEG stream.
+    pub fn mjpeg<P: AsRef<Path>>(path: P) -> Result<FileSource<MJPG>> {
+        let data = std::fs::read(path.as_ref())?;
+        let mut chunks = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            // Skip anything between images, like the multipart headers from an HTTP stream.
+            let Some(start) = data[offset..].windows(2).position(|w| w == [0xff, 0xd8]) else {
+                break;
+            };
+            let start = offset + start;
+            let Some((len, _, _)) = parse_jpeg(&data[start..]) else {
+                // Truncated image at the end, probably from a recording that was cut off.
+                break;
+            };
+            chunks.push(Chunk::Range {
+                offset: start as u64,
+                len,
+            });
+            offset = start + len;
+        }
+        FileSource::with_chunks(path.as_ref().to_owned(), chunks, None)
+    }
+}
+
+impl<F: PixelFormat> FileSource<F> {
+    /// A file of unpadded `width` x `height` frames one after another, with nothing in between.
+    pub fn raw<P: AsRef<Path>>(path: P, width: usize, height: usize) -> Result<FileSource<F>> {
+        let frame_len = width * height * F::byte_count();
+        if frame_len == 0 {
+            return Err(Error::FrameData);
+        }
+        let file_len = std::fs::metadata(path.as_ref())?.len() as usize;
+        let chunks = (0..file_len / frame_len).map(|i| Chunk::Range {
+            offset: (i * frame_len) as u64,
+            len: frame_len,
+        }).collect();
+        FileSource::with_chunks(path.as_ref().to_owned(), chunks, Some((width, height)))
+    }
+
+    fn with_chunks(path: PathBuf, chunks: Vec<Chunk>, dims: Option<(usize, usize)>) -> Result<FileSource<F>> {
+        if chunks.is_empty() {
+            return Err(Error::NoFrameData);
+        }
+        Ok(FileSource {
+            path,
+            chunks,
+            dims,
+            repeat: Repeat::Loop,
+            pacing: Pacing::Unpaced,
+            clock: PlayClock::new(),
+            position: None,
+            current: None,
+            _format: PhantomData,
+        })
+    }
+
+    /// Defaults to `Repeat::Loop`.
+    pub fn repeat(&mut self, repeat: Repeat) -> &mut Self {
+        self.repeat = repeat;
+        self
+    }
+
+    /// Defaults to `Pacing::Unpaced`.
+    pub fn pacing(&mut self, pacing: Pacing) -> &mut Self {
+        self.pacing = pacing;
+        self
+    }
+
+    /// Number of frames in one pass through the file(s).
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    /// Goes back to the first frame.
+    pub fn rewind(&mut self) {
+        self.clock.reset();
+        self.position = None;
+        self.current = None;
+    }
+
+    fn load(&self, index: usize) -> Result<Frame<F>> {
+        let data = match &self.chunks[index] {
+            Chunk::File(path) => std::fs::read(path)?,
+            Chunk::Range { offset, len } => {
+                let mut file = File::open(&self.path)?;
+                file.seek(SeekFrom::Start(*offset))?;
+                let mut data = vec![0; *len];
+                file.read_exact(&mut data)?;
+                data
+            }
+        };
+        let (width, height) = match self.dims {
+            Some(dims) => dims,
+            None => {
+                let (_, width, height) = parse_jpeg(&data).ok_or(Error::FrameData)?;
+                (width, height)
+            }
+        };
+        Ok(Frame::new(data, width, height))
+    }
+}
+
+impl<F: PixelFormat> FrameSource<F> for FileSource<F> {
+    fn get_frame(&mut self) -> Result<Option<Arc<Frame<F>>>> {
+        let Some(now) = self.clock.now() else {
+            return Ok(None);
+        };
+
+        let position = match self.pacing {
+            Pacing::RealTime(fps) => (now.as_secs_f64() * fps) as usize,
+            Pacing::Unpaced => self.position.map_or(0, |p| p + 1),
+        };
+        if self.repeat == Repeat::Once && position >= self.chunks.len() {
+            self.current = None;
+            return Ok(None);
+        }
+        if self.position != Some(position) || self.current.is_none() {
+            let frame = self.load(position % self.chunks.len())?;
+            self.current = Some(Arc::new(frame));
+            self.position = Some(position);
+        }
+        Ok(self.current.clone())
+    }
+
+    fn start(&mut self) -> Result<()> {
+        self.clock.start();
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.clock.stop();
+        Ok(())
+    }
+
+    fn last_frame_id(&self) -> usize {
+        // Counts skipped frames too, the same way a camera's would.
+        self.position.map_or(0, |p| p + 1)
+    }
+}
+
+/// Walks the markers of the jpg at the start of `data`, returning its length up through the
+/// EOI marker and its width and height from the SOF header.
+pub(crate) fn parse_jpeg(data: &[u8]) -> Option<(usize, usize, usize)> {
+    if data.len() < 4 || data[0..2] != [0xff, 0xd8] {
+        return None;
+    }
+    let mut dims = None;
+    let mut i = 2;
+    loop {
+        // Markers can be padded with any number of 0xff bytes.
+        while *data.get(i)? == 0xff && *data.get(i + 1)? == 0xff {
+            i += 1;
+        }
+        if *data.get(i)? != 0xff {
+            return None;
+        }
+        let marker = *data.get(i + 1)?;
+        match marker {
+            0xd9 => return dims.map(|(w, h)| (i + 2, w, h)),
+            // Standalone markers, with no length.
+            0x01 | 0xd0..=0xd7 => {
+                i += 2;
+                continue;
+            }
+            _ => {}
+        }
+        let len = u16::from_be_bytes([*data.get(i + 2)?, *data.get(i + 3)?]) as usize;
+        let segment = data.get(i + 4..i + 2 + len)?;
+        // Every SOFn but DHT (c4), JPG (c8), and DAC (cc).
+        if matches!(marker, 0xc0..=0xcf) && !matches!(marker, 0xc4 | 0xc8 | 0xcc) {
+            let height = u16::from_be_bytes([*segment.get(1)?, *segment.get(2)?]) as usize;
+            let width = u16::from_be_bytes([*segment.get(3)?, *segment.get(4)?]) as usize;
+            dims = Some((width, height));
+        }
+        i += 2 + len;
+        if marker == 0xda {
+            // Entropy-coded data follows SOS. The only 0xff bytes in it are stuffed (ff 00) or
+            // restart markers, so the next anything else is a real marker.
+            while *data.get(i)? != 0xff || matches!(data.get(i + 1)?, 0x00 | 0xd0..=0xd7) {
+                i += 1;
+            }
+        }
+    }
+}
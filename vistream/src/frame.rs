@@ -9,6 +9,10 @@ use vistream_protocol::camera::PixelFormat as ProtoPixelFormat;
 pub trait PixelFormat: Clone {
     fn byte_count() -> usize;
     fn proto_format() -> ProtoPixelFormat;
+    /// `proto_format`, without the panic for formats that don't have one.
+    fn try_proto_format() -> Option<ProtoPixelFormat> {
+        Some(Self::proto_format())
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -53,11 +57,69 @@ impl PixelFormat for MJPG {
     fn proto_format() -> ProtoPixelFormat {ProtoPixelFormat::MJPEG}
 }
 
+/// H.264 Annex B byte stream for a single frame. Not pixelable; see `transform::H264Source`.
+#[derive(Clone, Copy)]
+pub struct H264;
+impl PixelFormat for H264 {
+    fn byte_count() -> usize {1}
+    fn proto_format() -> ProtoPixelFormat {panic!("H264 does not translate to PixelFormat");}
+    fn try_proto_format() -> Option<ProtoPixelFormat> {None}
+}
+
+#[derive(Clone, Copy)]
+pub struct SRGGB8;
+impl PixelFormat for SRGGB8 {
+    fn byte_count() -> usize {1}
+    fn proto_format() -> ProtoPixelFormat {ProtoPixelFormat::SRGGB8}
+}
+
+/// 10-bit Bayer samples, stored in the low bits of little-endian 16-bit words.
+#[derive(Clone, Copy)]
+pub struct SRGGB10;
+impl PixelFormat for SRGGB10 {
+    fn byte_count() -> usize {2}
+    fn proto_format() -> ProtoPixelFormat {ProtoPixelFormat::SRGGB10}
+}
+
+/// 12-bit Bayer samples, stored in the low bits of little-endian 16-bit words.
+#[derive(Clone, Copy)]
+pub struct SRGGB12;
+impl PixelFormat for SRGGB12 {
+    fn byte_count() -> usize {2}
+    fn proto_format() -> ProtoPixelFormat {ProtoPixelFormat::SRGGB12}
+}
+
+/// CSI-2 packed 10-bit Bayer (4 samples in 5 bytes). Not pixelable; see `transform::Unpack`.
+#[derive(Clone, Copy)]
+pub struct SRGGB10P;
+impl PixelFormat for SRGGB10P {
+    fn byte_count() -> usize {1}
+    fn proto_format() -> ProtoPixelFormat {ProtoPixelFormat::SRGGB10P}
+}
+
+/// CSI-2 packed 12-bit Bayer (2 samples in 3 bytes). Not pixelable; see `transform::Unpack`.
+#[derive(Clone, Copy)]
+pub struct SRGGB12P;
+impl PixelFormat for SRGGB12P {
+    fn byte_count() -> usize {1}
+    fn proto_format() -> ProtoPixelFormat {ProtoPixelFormat::SRGGB12P}
+}
+
+/// Bayer samples scaled to the full range of little-endian 16-bit words, regardless of the
+/// sensor's bit depth. This is what `transform::Unpack` produces.
+#[derive(Clone, Copy)]
+pub struct SRGGB16;
+impl PixelFormat for SRGGB16 {
+    fn byte_count() -> usize {2}
+    fn proto_format() -> ProtoPixelFormat {panic!("SRGGB16 does not translate to PixelFormat");}
+    fn try_proto_format() -> Option<ProtoPixelFormat> {None}
+}
+
 #[derive(Clone, Copy)]
 pub struct Luma;
 impl PixelFormat for Luma {
     fn byte_count() -> usize {1}
-    fn proto_format() -> ProtoPixelFormat {panic!("Luma does not translate to PixelFormat");}
+    fn proto_format() -> ProtoPixelFormat {ProtoPixelFormat::Luma}
 }
 
 #[derive(Clone, Copy)]
@@ -65,6 +127,7 @@ pub struct Raw<const N: usize>;
 impl<const N: usize> PixelFormat for Raw<N> {
     fn byte_count() -> usize {N}
     fn proto_format() -> ProtoPixelFormat {panic!("Raw<{}> does not translate to PixelFormat", N)}
+    fn try_proto_format() -> Option<ProtoPixelFormat> {None}
 }
 
 
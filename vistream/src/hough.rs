@@ -0,0 +1,574 @@
+use std::f64::consts::PI;
+
+use crate::camera::{FrameSource, Locate};
+use crate::contour::{normalize_angle, Point};
+use crate::error::Result;
+use crate::frame::{Frame, Luma, Pixelate};
+use vistream_protocol::stream::LocationData;
+
+// Finer than this and the accumulators get huge for no real gain.
+const MIN_RHO_RESOLUTION: f64 = 0.1;
+const MIN_THETA_RESOLUTION: f64 = PI / 3600.0;
+
+/// A line in Hesse normal form: every point satisfying `x*cos(theta) + y*sin(theta) = rho`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Line {
+    pub rho: f64,
+    /// Angle of the line's normal, in radians, within [0, pi).
+    pub theta: f64,
+    pub votes: u32,
+}
+
+impl Line {
+    /// Angle of the line itself from the x axis, in degrees, within (-90, 90].
+    pub fn angle(&self) -> f64 {
+        normalize_angle(self.theta.to_degrees() + 90.0)
+    }
+}
+
+impl From<&Line> for LocationData {
+    fn from(line: &Line) -> LocationData {
+        // The closest point on the line to the origin stands in for its position.
+        let (sin, cos) = line.theta.sin_cos();
+        LocationData {
+            x: line.rho * cos,
+            y: line.rho * sin,
+            roll: Some(line.angle()),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Segment {
+    pub start: Point,
+    pub end: Point,
+}
+
+impl Segment {
+    pub fn length(&self) -> f64 {
+        (self.end.x - self.start.x).hypot(self.end.y - self.start.y)
+    }
+
+    /// Angle from the x axis, in degrees, within (-90, 90].
+    pub fn angle(&self) -> f64 {
+        normalize_angle((self.end.y - self.start.y).atan2(self.end.x - self.start.x).to_degrees())
+    }
+}
+
+impl From<&Segment> for LocationData {
+    fn from(segment: &Segment) -> LocationData {
+        LocationData {
+            x: (segment.start.x + segment.end.x) / 2.0,
+            y: (segment.start.y + segment.end.y) / 2.0,
+            width: Some(segment.length()),
+            roll: Some(segment.angle()),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Circle {
+    pub center: Point,
+    pub radius: f64,
+    pub votes: u32,
+}
+
+impl From<&Circle> for LocationData {
+    fn from(circle: &Circle) -> LocationData {
+        LocationData {
+            x: circle.center.x,
+            y: circle.center.y,
+            width: Some(circle.radius * 2.0),
+            height: Some(circle.radius * 2.0),
+            ..Default::default()
+        }
+    }
+}
+
+// Precomputed (cos, sin) pairs and accumulator geometry shared by both line transforms.
+struct LineSpace {
+    trig: Vec<(f64, f64)>,
+    rho_resolution: f64,
+    theta_resolution: f64,
+    rho_offset: usize,
+    rho_count: usize,
+}
+
+impl LineSpace {
+    fn new(width: usize, height: usize, rho_resolution: f64, theta_resolution: f64) -> LineSpace {
+        let theta_count = ((PI / theta_resolution).round() as usize).max(1);
+        let trig = (0..theta_count)
+            .map(|t| {
+                let (sin, cos) = (t as f64 * theta_resolution).sin_cos();
+                (cos, sin)
+            })
+            .collect();
+        let max_rho = ((width + height) as f64 / rho_resolution).ceil() as usize;
+        LineSpace {
+            trig,
+            rho_resolution,
+            theta_resolution,
+            rho_offset: max_rho,
+            rho_count: max_rho * 2 + 1,
+        }
+    }
+
+    fn rho_index(&self, x: usize, y: usize, t: usize) -> usize {
+        let (cos, sin) = self.trig[t];
+        let rho = x as f64 * cos + y as f64 * sin;
+        ((rho / self.rho_resolution).round() as isize + self.rho_offset as isize) as usize
+    }
+
+    fn vote(&self, acc: &mut [u32], x: usize, y: usize) {
+        for t in 0..self.trig.len() {
+            acc[t * self.rho_count + self.rho_index(x, y, t)] += 1;
+        }
+    }
+
+    fn unvote(&self, acc: &mut [u32], x: usize, y: usize) {
+        for t in 0..self.trig.len() {
+            let bin = &mut acc[t * self.rho_count + self.rho_index(x, y, t)];
+            *bin = bin.saturating_sub(1);
+        }
+    }
+}
+
+fn edge_points(frame: &Frame<Luma>) -> Vec<(usize, usize)> {
+    let width = frame.width();
+    frame.bytes().iter()
+        .enumerate()
+        .filter(|(_, &v)| v != 0)
+        .map(|(i, _)| (i % width, i / width))
+        .collect()
+}
+
+/// Standard Hough line transform over an edge frame, where any nonzero pixel is an edge.
+pub struct HoughLines {
+    threshold: u32,
+    rho_resolution: f64,
+    theta_resolution: f64,
+    max_lines: Option<usize>,
+}
+
+impl HoughLines {
+    /// `threshold` is the minimum number of edge pixels that must agree on a line.
+    pub fn new(threshold: u32) -> HoughLines {
+        HoughLines {
+            threshold,
+            rho_resolution: 1.0,
+            theta_resolution: PI / 180.0,
+            max_lines: None,
+        }
+    }
+
+    /// Distance resolution of the accumulator, in pixels. Defaults to 1, and can't go below
+    /// 0.1.
+    pub fn rho_resolution(&mut self, rho: f64) -> &mut Self {
+        self.rho_resolution = rho.max(MIN_RHO_RESOLUTION);
+        self
+    }
+
+    /// Angle resolution of the accumulator, in radians. Defaults to 1 degree, and can't go
+    /// below a twentieth of one.
+    pub fn theta_resolution(&mut self, theta: f64) -> &mut Self {
+        self.theta_resolution = theta.max(MIN_THETA_RESOLUTION);
+        self
+    }
+
+    pub fn max_lines(&mut self, count: usize) -> &mut Self {
+        self.max_lines = (count != 0).then_some(count);
+        self
+    }
+
+    pub fn detect(&self, frame: &Frame<Luma>) -> Vec<Line> {
+        let space = LineSpace::new(frame.width(), frame.height(), self.rho_resolution, self.theta_resolution);
+        let rho_count = space.rho_count;
+        let mut acc = vec![0u32; space.trig.len() * rho_count];
+        for (x, y) in edge_points(frame) {
+            space.vote(&mut acc, x, y);
+        }
+
+        let mut lines = Vec::new();
+        for t in 0..space.trig.len() {
+            for r in 0..rho_count {
+                let i = t * rho_count + r;
+                let votes = acc[i];
+                if votes < self.threshold {
+                    continue;
+                }
+                // local maximum against the four direct neighbors
+                let left = if r > 0 { acc[i - 1] } else { 0 };
+                let right = if r + 1 < rho_count { acc[i + 1] } else { 0 };
+                let up = if t > 0 { acc[i - rho_count] } else { 0 };
+                let down = if t + 1 < space.trig.len() { acc[i + rho_count] } else { 0 };
+                if votes > left && votes >= right && votes > up && votes >= down {
+                    lines.push(Line {
+                        rho: (r as f64 - space.rho_offset as f64) * space.rho_resolution,
+                        theta: t as f64 * space.theta_resolution,
+                        votes,
+                    });
+                }
+            }
+        }
+
+        lines.sort_by_key(|line| std::cmp::Reverse(line.votes));
+        if let Some(max) = self.max_lines {
+            lines.truncate(max);
+        }
+        lines
+    }
+}
+
+impl<S: FrameSource<Luma>> Locate<Luma, S> for HoughLines {
+    fn locate(&mut self, source: &mut S) -> Result<Option<Vec<LocationData>>> {
+        let Some(frame) = source.get_frame()? else {
+            return Ok(None);
+        };
+        Ok(Some(self.detect(&frame).iter().map(LocationData::from).collect()))
+    }
+}
+
+/// Progressive probabilistic Hough transform, producing finite segments rather than
+/// infinite lines. Edge frames are treated the same as in `HoughLines`.
+pub struct HoughSegments {
+    threshold: u32,
+    rho_resolution: f64,
+    theta_resolution: f64,
+    min_length: f64,
+    max_gap: usize,
+    max_lines: Option<usize>,
+}
+
+impl HoughSegments {
+    pub fn new(threshold: u32) -> HoughSegments {
+        HoughSegments {
+            threshold,
+            rho_resolution: 1.0,
+            theta_resolution: PI / 180.0,
+            min_length: 0.0,
+            max_gap: 0,
+            max_lines: None,
+        }
+    }
+
+    pub fn rho_resolution(&mut self, rho: f64) -> &mut Self {
+        self.rho_resolution = rho.max(MIN_RHO_RESOLUTION);
+        self
+    }
+
+    pub fn theta_resolution(&mut self, theta: f64) -> &mut Self {
+        self.theta_resolution = theta.max(MIN_THETA_RESOLUTION);
+        self
+    }
+
+    /// Segments shorter than this, in pixels, are discarded.
+    pub fn min_length(&mut self, length: f64) -> &mut Self {
+        self.min_length = length;
+        self
+    }
+
+    /// Largest run of missing edge pixels that still counts as one segment.
+    pub fn max_gap(&mut self, gap: usize) -> &mut Self {
+        self.max_gap = gap;
+        self
+    }
+
+    pub fn max_lines(&mut self, count: usize) -> &mut Self {
+        self.max_lines = (count != 0).then_some(count);
+        self
+    }
+
+    pub fn detect(&self, frame: &Frame<Luma>) -> Vec<Segment> {
+        let width = frame.width();
+        let height = frame.height();
+        let space = LineSpace::new(width, height, self.rho_resolution, self.theta_resolution);
+        let rho_count = space.rho_count;
+        let mut acc = vec![0u32; space.trig.len() * rho_count];
+
+        let mut points = edge_points(frame);
+        let mut mask = vec![false; width * height];
+        for &(x, y) in points.iter() {
+            mask[y * width + x] = true;
+        }
+
+        // Visiting points in a scrambled (but repeatable) order is what makes this
+        // progressive; a fixed seed keeps results stable from frame to frame.
+        let mut seed = 0x2545f4914f6cdd1du64;
+        for i in (1..points.len()).rev() {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            points.swap(i, (seed % (i as u64 + 1)) as usize);
+        }
+
+        let mut segments = Vec::new();
+        for &(x, y) in points.iter() {
+            if !mask[y * width + x] {
+                continue;
+            }
+
+            let mut best = (0, 0);
+            for t in 0..space.trig.len() {
+                let i = t * rho_count + space.rho_index(x, y, t);
+                acc[i] += 1;
+                if acc[i] > best.0 {
+                    best = (acc[i], t);
+                }
+            }
+            if best.0 < self.threshold {
+                continue;
+            }
+
+            // Step along the line one pixel at a time on its dominant axis.
+            let (cos, sin) = space.trig[best.1];
+            let (dx, dy) = (-sin, cos);
+            let (step_x, step_y) = if dx.abs() > dy.abs() {
+                (dx.signum(), dy / dx.abs())
+            } else {
+                (dx / dy.abs(), dy.signum())
+            };
+
+            let walk = |direction: f64, n: usize| -> Option<(usize, usize)> {
+                let px = (x as f64 + direction * step_x * n as f64).round();
+                let py = (y as f64 + direction * step_y * n as f64).round();
+                (px >= 0.0 && py >= 0.0 && (px as usize) < width && (py as usize) < height)
+                    .then_some((px as usize, py as usize))
+            };
+
+            let mut ends = [(x, y); 2];
+            let mut lengths = [0usize; 2];
+            for (k, direction) in [1.0, -1.0].into_iter().enumerate() {
+                let mut gap = 0;
+                let mut n = 1;
+                while let Some((px, py)) = walk(direction, n) {
+                    if mask[py * width + px] {
+                        gap = 0;
+                        ends[k] = (px, py);
+                        lengths[k] = n;
+                    } else {
+                        gap += 1;
+                        if gap > self.max_gap {
+                            break;
+                        }
+                    }
+                    n += 1;
+                }
+            }
+
+            let segment = Segment {
+                start: Point::new(ends[1].0 as f64, ends[1].1 as f64),
+                end: Point::new(ends[0].0 as f64, ends[0].1 as f64),
+            };
+            let good = segment.length() >= self.min_length;
+
+            // Points along the walked span are consumed whether or not the segment is kept,
+            // but only a kept segment gives back its votes.
+            for (k, direction) in [1.0, -1.0].into_iter().enumerate() {
+                for n in 0..=lengths[k] {
+                    let Some((px, py)) = walk(direction, n) else {
+                        break;
+                    };
+                    if mask[py * width + px] {
+                        if good {
+                            space.unvote(&mut acc, px, py);
+                        }
+                        mask[py * width + px] = false;
+                    }
+                }
+            }
+
+            if good {
+                segments.push(segment);
+                if self.max_lines.is_some_and(|max| segments.len() >= max) {
+                    break;
+                }
+            }
+        }
+
+        segments
+    }
+}
+
+impl<S: FrameSource<Luma>> Locate<Luma, S> for HoughSegments {
+    fn locate(&mut self, source: &mut S) -> Result<Option<Vec<LocationData>>> {
+        let Some(frame) = source.get_frame()? else {
+            return Ok(None);
+        };
+        Ok(Some(self.detect(&frame).iter().map(LocationData::from).collect()))
+    }
+}
+
+/// Gradient-based Hough circle transform over a grayscale frame. Edges are found with a
+/// Sobel operator, and each one votes for centers along its gradient direction.
+pub struct HoughCircles {
+    threshold: u32,
+    resolution: f64,
+    edge_threshold: f64,
+    min_distance: f64,
+    min_radius: usize,
+    max_radius: Option<usize>,
+    max_circles: Option<usize>,
+}
+
+impl HoughCircles {
+    /// `threshold` is the minimum number of edge votes for a circle center.
+    pub fn new(threshold: u32) -> HoughCircles {
+        HoughCircles {
+            threshold,
+            resolution: 1.0,
+            edge_threshold: 100.0,
+            min_distance: 1.0,
+            min_radius: 1,
+            max_radius: None,
+            max_circles: None,
+        }
+    }
+
+    /// Size of one accumulator cell, in pixels. Larger values are faster and more
+    /// forgiving of imperfect circles. Defaults to 1.
+    pub fn resolution(&mut self, resolution: f64) -> &mut Self {
+        self.resolution = resolution.max(1.0);
+        self
+    }
+
+    /// Minimum Sobel gradient magnitude for a pixel to count as an edge.
+    pub fn edge_threshold(&mut self, threshold: f64) -> &mut Self {
+        self.edge_threshold = threshold;
+        self
+    }
+
+    /// Minimum distance between the centers of two detected circles.
+    pub fn min_distance(&mut self, distance: f64) -> &mut Self {
+        self.min_distance = distance;
+        self
+    }
+
+    pub fn min_radius(&mut self, radius: usize) -> &mut Self {
+        self.min_radius = radius.max(1);
+        self
+    }
+
+    pub fn max_radius(&mut self, radius: usize) -> &mut Self {
+        self.max_radius = (radius != 0).then_some(radius);
+        self
+    }
+
+    pub fn max_circles(&mut self, count: usize) -> &mut Self {
+        self.max_circles = (count != 0).then_some(count);
+        self
+    }
+
+    pub fn detect(&self, frame: &Frame<Luma>) -> Vec<Circle> {
+        let width = frame.width();
+        let height = frame.height();
+        if width < 3 || height < 3 {
+            return Vec::new();
+        }
+        let bytes = frame.bytes();
+        let max_radius = self.max_radius.unwrap_or(width.max(height));
+
+        let acc_width = (width as f64 / self.resolution).ceil() as usize + 1;
+        let acc_height = (height as f64 / self.resolution).ceil() as usize + 1;
+        let mut acc = vec![0u32; acc_width * acc_height];
+
+        let px = |x: usize, y: usize| bytes[y * width + x] as f64;
+        let mut edges = Vec::new();
+        for y in 1..height - 1 {
+            for x in 1..width - 1 {
+                let gx = px(x + 1, y - 1) + 2.0 * px(x + 1, y) + px(x + 1, y + 1)
+                    - px(x - 1, y - 1) - 2.0 * px(x - 1, y) - px(x - 1, y + 1);
+                let gy = px(x - 1, y + 1) + 2.0 * px(x, y + 1) + px(x + 1, y + 1)
+                    - px(x - 1, y - 1) - 2.0 * px(x, y - 1) - px(x + 1, y - 1);
+                let magnitude = gx.hypot(gy);
+                if magnitude < self.edge_threshold {
+                    continue;
+                }
+                edges.push((x as f64, y as f64));
+
+                // The center may lie on either side of the edge, depending on whether the
+                // circle is brighter or darker than its surroundings.
+                // Each ray votes at most once per accumulator cell, otherwise coarse
+                // resolutions reward cells that rays merely run along.
+                let (ux, uy) = (gx / magnitude, gy / magnitude);
+                for sign in [1.0, -1.0] {
+                    let mut last = None;
+                    let mut r = self.min_radius as f64;
+                    while r <= max_radius as f64 {
+                        let cx = x as f64 + sign * ux * r;
+                        let cy = y as f64 + sign * uy * r;
+                        if cx < 0.0 || cy < 0.0 || cx >= width as f64 || cy >= height as f64 {
+                            break;
+                        }
+                        let cell = (cy / self.resolution) as usize * acc_width + (cx / self.resolution) as usize;
+                        if last != Some(cell) {
+                            acc[cell] += 1;
+                            last = Some(cell);
+                        }
+                        r += self.resolution / 2.0;
+                    }
+                }
+            }
+        }
+
+        let mut centers = Vec::new();
+        for ay in 1..acc_height - 1 {
+            for ax in 1..acc_width - 1 {
+                let i = ay * acc_width + ax;
+                let votes = acc[i];
+                if votes >= self.threshold
+                    && votes > acc[i - 1] && votes >= acc[i + 1]
+                    && votes > acc[i - acc_width] && votes >= acc[i + acc_width] {
+                    let cx = (ax as f64 + 0.5) * self.resolution;
+                    let cy = (ay as f64 + 0.5) * self.resolution;
+                    centers.push((votes, Point::new(cx, cy)));
+                }
+            }
+        }
+        centers.sort_by_key(|(votes, _)| std::cmp::Reverse(*votes));
+
+        let mut circles: Vec<Circle> = Vec::new();
+        let mut histogram = vec![0u32; max_radius + 1];
+        for (votes, center) in centers {
+            if circles.iter().any(|c| {
+                (c.center.x - center.x).hypot(c.center.y - center.y) < self.min_distance
+            }) {
+                continue;
+            }
+
+            // Pick the radius that the most edge pixels agree on, if any do.
+            histogram.fill(0);
+            for &(x, y) in edges.iter() {
+                let r = (x - center.x).hypot(y - center.y).round() as usize;
+                if r >= self.min_radius && r <= max_radius {
+                    histogram[r] += 1;
+                }
+            }
+            let best = histogram.iter().enumerate().filter(|(_, &count)| count > 0).max_by_key(|(_, &count)| count);
+            let Some((radius, _)) = best else {
+                continue;
+            };
+
+            circles.push(Circle {
+                center,
+                radius: radius as f64,
+                votes,
+            });
+            if self.max_circles.is_some_and(|max| circles.len() >= max) {
+                break;
+            }
+        }
+
+        circles
+    }
+}
+
+impl<S: FrameSource<Luma>> Locate<Luma, S> for HoughCircles {
+    fn locate(&mut self, source: &mut S) -> Result<Option<Vec<LocationData>>> {
+        let Some(frame) = source.get_frame()? else {
+            return Ok(None);
+        };
+        Ok(Some(self.detect(&frame).iter().map(LocationData::from).collect()))
+    }
+}
@@ -0,0 +1,248 @@
+//! MJPEG over plain HTTP, for browsers and dashboards that can't speak the vistream protocol.
+
+use crate::camera::{FrameSource, Worker};
+use crate::error::Result;
+use crate::frame::{Frame, MJPG};
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+const BOUNDARY: &str = "vistreamframe";
+// How long a snapshot waits for a frame before giving up
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(5);
+// Also how often client threads check whether the stream is being stopped
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+const INDEX: &str = "<!DOCTYPE html>\
+<html><head><title>vistream</title></head>\
+<body style=\"margin:0;background:#000\">\
+<img src=\"/stream.mjpg\" style=\"display:block;margin:auto;max-width:100%;max-height:100vh\">\
+</body></html>";
+
+/// The most recent frame, shared between the capture loop and every client.
+struct Latest {
+    // (frame id, frame)
+    frame: Mutex<(usize, Option<Arc<Frame<MJPG>>>)>,
+    updated: Condvar,
+    // Clients currently waiting on frames. The source only runs while this is nonzero.
+    clients: AtomicUsize,
+}
+
+impl Latest {
+    /// Waits for a frame newer than `last_id`, returning `None` on timeout.
+    fn wait_newer(&self, last_id: usize, timeout: Duration) -> Option<(usize, Arc<Frame<MJPG>>)> {
+        let guard = self.frame.lock().unwrap();
+        let (guard, _) = self.updated
+            .wait_timeout_while(guard, timeout, |(id, _)| *id == last_id)
+            .unwrap();
+        match &*guard {
+            (id, Some(frame)) if *id != last_id => Some((*id, frame.clone())),
+            _ => None,
+        }
+    }
+
+    fn current_id(&self) -> usize {
+        self.frame.lock().unwrap().0
+    }
+}
+
+/// Serves a `FrameSource<MJPG>` over HTTP:
+///
+/// - `/stream.mjpg`: a `multipart/x-mixed-replace` stream, for an `<img>` tag or a dashboard
+/// - `/snapshot.jpg`: the next frame, on its own
+/// - `/`: a page with the stream on it
+///
+/// Each client gets the newest frame whenever it's ready for one, so a slow client just
+/// skips frames instead of holding up the rest. The source is started while anyone is
+/// connected and stopped when the last one leaves.
+pub struct HttpMjpegStream {
+    worker: Worker,
+}
+
+impl HttpMjpegStream {
+    pub fn launch<S>(addr: SocketAddr, source: S) -> Result<HttpMjpegStream>
+    where S: FrameSource<MJPG> + Send + 'static {
+        let socket = TcpListener::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        let worker = Worker::spawn(move |kill_flag| {
+            let mut source = source;
+            let latest = Arc::new(Latest {
+                frame: Mutex::new((0, None)),
+                updated: Condvar::new(),
+                clients: AtomicUsize::new(0),
+            });
+            let mut running = false;
+            let mut last_source_id = None;
+
+            let res = (|| -> Result<()> {
+                while !kill_flag.load(Ordering::Acquire) {
+                    match socket.accept() {
+                        Ok((conn, _)) => {
+                            let latest = latest.clone();
+                            let kill_flag = kill_flag.clone();
+                            std::thread::spawn(move || {
+                                // Nothing to be done about a client that went away mid-response.
+                                let _ = handle(conn, &latest, &kill_flag);
+                            });
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            // do nothing
+                        }
+                        Err(e) => {
+                            return Err(e.into());
+                        }
+                    }
+
+                    if latest.clients.load(Ordering::Acquire) == 0 {
+                        if running {
+                            source.stop()?;
+                            running = false;
+                        }
+                        std::thread::sleep(std::time::Duration::from_millis(16)); // ~1 frame @ 60fps
+                        continue;
+                    }
+                    if !running {
+                        source.start()?;
+                        running = true;
+                    }
+
+                    match source.get_frame()? {
+                        Some(frame) if last_source_id != Some(source.last_frame_id()) => {
+                            last_source_id = Some(source.last_frame_id());
+                            let mut guard = latest.frame.lock().unwrap();
+                            *guard = (guard.0 + 1, Some(frame));
+                            latest.updated.notify_all();
+                        }
+                        _ => std::thread::sleep(std::time::Duration::from_millis(1)),
+                    }
+                }
+                Ok(())
+            })();
+
+            // However serving ended, the clients have to find out, or they'd wait on frames
+            // forever. The flag tells them it's over, and waking them makes them look.
+            kill_flag.store(true, Ordering::Release);
+            latest.updated.notify_all();
+            let stopped = if running { source.stop() } else { Ok(()) };
+            // the first error is the interesting one
+            res.and(stopped)
+        });
+        Ok(HttpMjpegStream {
+            worker
+        })
+    }
+
+    pub fn stop(mut self) -> Result<()> {
+        match self.worker.join() {
+            Some(err) => Err(err),
+            None => Ok(())
+        }
+    }
+}
+
+/// Counts as a client for as long as it's alive.
+struct ClientGuard<'a>(&'a Latest);
+
+impl<'a> ClientGuard<'a> {
+    fn new(latest: &'a Latest) -> ClientGuard<'a> {
+        latest.clients.fetch_add(1, Ordering::AcqRel);
+        ClientGuard(latest)
+    }
+}
+
+impl Drop for ClientGuard<'_> {
+    fn drop(&mut self) {
+        self.0.clients.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+fn handle(mut conn: TcpStream, latest: &Latest, kill_flag: &AtomicBool) -> io::Result<()> {
+    conn.set_nonblocking(false)?;
+    conn.set_read_timeout(Some(Duration::from_secs(2)))?;
+    // A client that stops reading gets dropped instead of piling up frames forever.
+    conn.set_write_timeout(Some(Duration::from_secs(2)))?;
+
+    let Some((method, path)) = read_request(&mut conn)? else {
+        return respond(&mut conn, "400 Bad Request", "text/plain", b"bad request\n");
+    };
+    if method != "GET" {
+        return respond(&mut conn, "405 Method Not Allowed", "text/plain", b"method not allowed\n");
+    }
+    // Ignore any query string, which browsers like to use to get around caching.
+    let path = path.split(['?', '#']).next().unwrap_or("");
+
+    match path {
+        "/" | "/index.html" => respond(&mut conn, "200 OK", "text/html", INDEX.as_bytes()),
+        "/snapshot.jpg" => {
+            let _client = ClientGuard::new(latest);
+            // The stored frame could be from whenever anyone last wanted one, so wait for a
+            // fresh one.
+            match latest.wait_newer(latest.current_id(), SNAPSHOT_TIMEOUT) {
+                Some((_, frame)) => respond(&mut conn, "200 OK", "image/jpeg", frame.bytes()),
+                None => respond(&mut conn, "503 Service Unavailable", "text/plain", b"no frames available\n"),
+            }
+        }
+        "/stream.mjpg" => {
+            let _client = ClientGuard::new(latest);
+            write!(conn, "HTTP/1.1 200 OK\r\n\
+                Content-Type: multipart/x-mixed-replace; boundary={}\r\n\
+                Cache-Control: no-cache, no-store, must-revalidate\r\n\
+                Pragma: no-cache\r\n\
+                Access-Control-Allow-Origin: *\r\n\
+                Connection: close\r\n\r\n", BOUNDARY)?;
+            conn.flush()?;
+
+            let mut last_id = 0;
+            while !kill_flag.load(Ordering::Acquire) {
+                let Some((id, frame)) = latest.wait_newer(last_id, POLL_INTERVAL) else {
+                    continue;
+                };
+                last_id = id;
+                let data = frame.bytes();
+                write!(conn, "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n", BOUNDARY, data.len())?;
+                conn.write_all(data)?;
+                conn.write_all(b"\r\n")?;
+                conn.flush()?;
+            }
+            Ok(())
+        }
+        _ => respond(&mut conn, "404 Not Found", "text/plain", b"not found\n"),
+    }
+}
+
+/// Reads the request head, returning the method and path. The rest of the headers don't
+/// matter here.
+fn read_request(conn: &mut TcpStream) -> io::Result<Option<(String, String)>> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > 16 * 1024 {
+            return Ok(None);
+        }
+        let n = conn.read(&mut buf)?;
+        if n == 0 {
+            return Ok(None);
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut parts = head.lines().next().unwrap_or("").split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => Ok(Some((method.to_string(), path.to_string()))),
+        _ => Ok(None),
+    }
+}
+
+fn respond(conn: &mut TcpStream, status: &str, content_type: &str, body: &[u8]) -> io::Result<()> {
+    write!(conn, "HTTP/1.1 {}\r\n\
+        Content-Type: {}\r\n\
+        Content-Length: {}\r\n\
+        Cache-Control: no-cache, no-store, must-revalidate\r\n\
+        Access-Control-Allow-Origin: *\r\n\
+        Connection: close\r\n\r\n", status, content_type, body.len())?;
+    conn.write_all(body)?;
+    conn.flush()
+}
@@ -0,0 +1,285 @@
+//! JPEG codec backends used by `JPGSource` and `JPGUnpacker`.
+//!
+//! The `jpeg` feature uses libjpeg-turbo through the `turbojpeg` crate, while `jpeg-rs` uses
+//! pure-Rust crates, which is much easier to cross-compile. When both are enabled, the
+//! `Default*` aliases pick turbojpeg.
+
+use crate::error::{Error, Result};
+
+/// Byte layout of the raw pixels handed to or produced by a codec.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JpegLayout {
+    RGB,
+    BGR,
+    RGBA,
+    BGRA,
+    Gray,
+}
+
+impl JpegLayout {
+    pub fn channels(&self) -> usize {
+        match self {
+            JpegLayout::RGB | JpegLayout::BGR => 3,
+            JpegLayout::RGBA | JpegLayout::BGRA => 4,
+            JpegLayout::Gray => 1,
+        }
+    }
+}
+
+/// Chroma subsampling, named the same way as turbojpeg's `Subsamp`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Subsampling {
+    /// 4:4:4
+    None,
+    /// 4:2:2
+    Sub2x1,
+    /// 4:2:0
+    Sub2x2,
+    /// Luma only
+    Gray,
+}
+
+pub trait JpegEncoder {
+    /// Compresses tightly packed `pixels` into `out`, replacing whatever was there.
+    fn encode(&mut self, pixels: &[u8], width: usize, height: usize, layout: JpegLayout, out: &mut Vec<u8>) -> Result<()>;
+    /// Quality from 1 to 100.
+    fn set_quality(&mut self, quality: u8) -> Result<()>;
+    fn set_subsampling(&mut self, subsampling: Subsampling) -> Result<()>;
+}
+
+pub trait JpegDecoder {
+    /// Decompresses `data` into `out`, which must be exactly `width * height * channels` bytes
+    /// for the image being decoded.
+    fn decode(&mut self, data: &[u8], layout: JpegLayout, out: &mut [u8]) -> Result<()>;
+}
+
+#[cfg(feature = "jpeg")]
+pub type DefaultEncoder = turbojpeg::Compressor;
+#[cfg(feature = "jpeg")]
+pub type DefaultDecoder = turbojpeg::Decompressor;
+
+#[cfg(all(feature = "jpeg-rs", not(feature = "jpeg")))]
+pub type DefaultEncoder = RustEncoder;
+#[cfg(all(feature = "jpeg-rs", not(feature = "jpeg")))]
+pub type DefaultDecoder = RustDecoder;
+
+pub fn default_encoder() -> Result<DefaultEncoder> {
+    #[cfg(feature = "jpeg")]
+    return turbojpeg::Compressor::new().map_err(|_| Error::Unknown);
+    #[cfg(not(feature = "jpeg"))]
+    return Ok(RustEncoder::new());
+}
+
+pub fn default_decoder() -> Result<DefaultDecoder> {
+    #[cfg(feature = "jpeg")]
+    return turbojpeg::Decompressor::new().map_err(|_| Error::Unknown);
+    #[cfg(not(feature = "jpeg"))]
+    return Ok(RustDecoder::new());
+}
+
+#[cfg(feature = "jpeg")]
+mod turbo {
+    use super::*;
+    use turbojpeg::{Compressor, Decompressor, Image, PixelFormat, Subsamp};
+
+    impl From<JpegLayout> for PixelFormat {
+        fn from(layout: JpegLayout) -> PixelFormat {
+            match layout {
+                JpegLayout::RGB => PixelFormat::RGB,
+                JpegLayout::BGR => PixelFormat::BGR,
+                JpegLayout::RGBA => PixelFormat::RGBA,
+                JpegLayout::BGRA => PixelFormat::BGRA,
+                JpegLayout::Gray => PixelFormat::GRAY,
+            }
+        }
+    }
+
+    impl From<Subsampling> for Subsamp {
+        fn from(subsampling: Subsampling) -> Subsamp {
+            match subsampling {
+                Subsampling::None => Subsamp::None,
+                Subsampling::Sub2x1 => Subsamp::Sub2x1,
+                Subsampling::Sub2x2 => Subsamp::Sub2x2,
+                Subsampling::Gray => Subsamp::Gray,
+            }
+        }
+    }
+
+    impl JpegEncoder for Compressor {
+        fn encode(&mut self, pixels: &[u8], width: usize, height: usize, layout: JpegLayout, out: &mut Vec<u8>) -> Result<()> {
+            let image = Image {
+                pixels,
+                width,
+                pitch: width * layout.channels(),
+                height,
+                format: layout.into(),
+            };
+            let len = self.buf_len(width, height).map_err(|_| Error::Unknown)?;
+            out.resize(len, 0);
+            let size = self.compress_to_slice(image, out).map_err(|_| Error::Unknown)?;
+            out.truncate(size);
+            Ok(())
+        }
+
+        fn set_quality(&mut self, quality: u8) -> Result<()> {
+            Compressor::set_quality(self, quality.clamp(1, 100) as i32).map_err(|_| Error::Unknown)
+        }
+
+        fn set_subsampling(&mut self, subsampling: Subsampling) -> Result<()> {
+            self.set_subsamp(subsampling.into()).map_err(|_| Error::Unknown)
+        }
+    }
+
+    impl JpegDecoder for Decompressor {
+        fn decode(&mut self, data: &[u8], layout: JpegLayout, out: &mut [u8]) -> Result<()> {
+            let header = self.read_header(data).map_err(|_| Error::FrameData)?;
+            if out.len() != header.width * header.height * layout.channels() {
+                return Err(Error::FrameData);
+            }
+            let image = Image {
+                pixels: out,
+                width: header.width,
+                pitch: header.width * layout.channels(),
+                height: header.height,
+                format: layout.into(),
+            };
+            self.decompress(data, image).map_err(|_| Error::FrameData)
+        }
+    }
+}
+
+#[cfg(feature = "jpeg-rs")]
+pub use self::rust::{RustEncoder, RustDecoder};
+
+#[cfg(feature = "jpeg-rs")]
+mod rust {
+    use super::*;
+    use jpeg_encoder::{ColorType, Encoder, SamplingFactor};
+    use jpeg_decoder::{Decoder, PixelFormat};
+
+    /// Pure-Rust encoder, backed by `jpeg-encoder`.
+    pub struct RustEncoder {
+        quality: u8,
+        subsampling: Subsampling,
+    }
+
+    impl RustEncoder {
+        pub fn new() -> RustEncoder {
+            RustEncoder {
+                quality: 90,
+                subsampling: Subsampling::Sub2x2,
+            }
+        }
+    }
+
+    impl Default for RustEncoder {
+        fn default() -> RustEncoder {
+            RustEncoder::new()
+        }
+    }
+
+    impl JpegEncoder for RustEncoder {
+        fn encode(&mut self, pixels: &[u8], width: usize, height: usize, layout: JpegLayout, out: &mut Vec<u8>) -> Result<()> {
+            let (Ok(w), Ok(h)) = (u16::try_from(width), u16::try_from(height)) else {
+                return Err(Error::FrameData);
+            };
+            let color = match layout {
+                JpegLayout::RGB => ColorType::Rgb,
+                JpegLayout::BGR => ColorType::Bgr,
+                JpegLayout::RGBA => ColorType::Rgba,
+                JpegLayout::BGRA => ColorType::Bgra,
+                JpegLayout::Gray => ColorType::Luma,
+            };
+            out.clear();
+            let mut encoder = Encoder::new(&mut *out, self.quality);
+            encoder.set_sampling_factor(match self.subsampling {
+                Subsampling::None | Subsampling::Gray => SamplingFactor::R_4_4_4,
+                Subsampling::Sub2x1 => SamplingFactor::R_4_2_2,
+                Subsampling::Sub2x2 => SamplingFactor::R_4_2_0,
+            });
+            if self.subsampling == Subsampling::Gray && layout != JpegLayout::Gray {
+                // jpeg-encoder has no luma-only mode for color input, so convert up front.
+                let gray = to_gray(pixels, layout);
+                return encoder.encode(&gray, w, h, ColorType::Luma).map_err(|_| Error::Unknown);
+            }
+            encoder.encode(pixels, w, h, color).map_err(|_| Error::Unknown)
+        }
+
+        fn set_quality(&mut self, quality: u8) -> Result<()> {
+            self.quality = quality.clamp(1, 100);
+            Ok(())
+        }
+
+        fn set_subsampling(&mut self, subsampling: Subsampling) -> Result<()> {
+            self.subsampling = subsampling;
+            Ok(())
+        }
+    }
+
+    fn to_gray(pixels: &[u8], layout: JpegLayout) -> Vec<u8> {
+        let (r, g, b) = match layout {
+            JpegLayout::RGB | JpegLayout::RGBA => (0, 1, 2),
+            JpegLayout::BGR | JpegLayout::BGRA => (2, 1, 0),
+            JpegLayout::Gray => return pixels.to_vec(),
+        };
+        pixels.chunks_exact(layout.channels()).map(|p| {
+            (0.299 * p[r] as f32 + 0.587 * p[g] as f32 + 0.114 * p[b] as f32) as u8
+        }).collect()
+    }
+
+    /// Pure-Rust decoder, backed by `jpeg-decoder`.
+    #[derive(Default)]
+    pub struct RustDecoder;
+
+    impl RustDecoder {
+        pub fn new() -> RustDecoder {
+            RustDecoder
+        }
+    }
+
+    impl JpegDecoder for RustDecoder {
+        fn decode(&mut self, data: &[u8], layout: JpegLayout, out: &mut [u8]) -> Result<()> {
+            let mut decoder = Decoder::new(data);
+            let pixels = decoder.decode().map_err(|_| Error::FrameData)?;
+            let Some(info) = decoder.info() else {
+                return Err(Error::FrameData);
+            };
+            let count = info.width as usize * info.height as usize;
+            if out.len() != count * layout.channels() {
+                return Err(Error::FrameData);
+            }
+
+            let channels = layout.channels();
+            match info.pixel_format {
+                PixelFormat::L8 => {
+                    for (o, &l) in out.chunks_exact_mut(channels).zip(pixels.iter()) {
+                        o.fill(l);
+                        if channels == 4 {
+                            o[3] = 255;
+                        }
+                    }
+                }
+                PixelFormat::RGB24 => {
+                    if layout == JpegLayout::Gray {
+                        out.copy_from_slice(&to_gray(&pixels, JpegLayout::RGB));
+                        return Ok(());
+                    }
+                    let swap = matches!(layout, JpegLayout::BGR | JpegLayout::BGRA);
+                    for (o, p) in out.chunks_exact_mut(channels).zip(pixels.chunks_exact(3)) {
+                        if swap {
+                            o[..3].copy_from_slice(&[p[2], p[1], p[0]]);
+                        } else {
+                            o[..3].copy_from_slice(p);
+                        }
+                        if channels == 4 {
+                            o[3] = 255;
+                        }
+                    }
+                }
+                // Neither of these come out of the cameras we support.
+                PixelFormat::L16 | PixelFormat::CMYK32 => return Err(Error::FrameData),
+            }
+            Ok(())
+        }
+    }
+}
@@ -4,12 +4,24 @@ pub mod stream;
 pub mod error;
 pub mod transform;
 pub mod client;
+pub mod contour;
+pub mod hough;
+pub mod dynamic;
+pub mod encode;
+pub mod file;
+pub mod pattern;
+pub mod record;
+pub mod http;
+#[cfg(any(feature = "jpeg", feature = "jpeg-rs"))]
+pub mod jpeg;
 
 #[cfg(target_os = "linux")]
 pub use crate::camera::{Camera, CameraConfig};
 pub use crate::camera::{FrameSource, Locate};
 pub use vistream_protocol::stream::{LocationData};
+pub use vistream_protocol::camera::{ControlValue, ControlInfo};
 pub use crate::frame::{Frame, Pixelate};
+pub use crate::dynamic::{AnyFrame, AnyFrameSource};
 
 #[cfg(feature = "ws")]
 pub mod ws;
@@ -0,0 +1,179 @@
+//! Synthetic frames, for testing pipelines without a camera.
+
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use crate::camera::FrameSource;
+use crate::error::Result;
+use crate::file::{Pacing, PlayClock};
+use crate::frame::{self, Frame, PixelFormat};
+
+// Frames for the moving target to go all the way around its path.
+const TARGET_PERIOD: f64 = 120.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pattern {
+    /// Eight vertical bars: white, yellow, cyan, green, magenta, red, blue, black.
+    ColorBars,
+    /// Black on the left to white on the right.
+    Gradient,
+    /// Black and white squares, with the given side length in pixels.
+    Checkerboard(usize),
+    /// A filled circle moving around a dark background. See `TestPattern::target`.
+    MovingTarget,
+}
+
+/// Formats that a `TestPattern` can be drawn in.
+pub trait PatternFormat: PixelFormat {
+    fn write(rgb: [u8; 3], out: &mut [u8]);
+}
+
+macro_rules! pattern_format {
+    ($fmt:ty, |$rgb:ident, $out:ident| $body:expr) => {
+        impl PatternFormat for $fmt {
+            fn write($rgb: [u8; 3], $out: &mut [u8]) {
+                $body
+            }
+        }
+    }
+}
+
+pattern_format!(frame::RGB, |rgb, out| out.copy_from_slice(&rgb));
+pattern_format!(frame::BGR, |rgb, out| out.copy_from_slice(&[rgb[2], rgb[1], rgb[0]]));
+pattern_format!(frame::RGBA, |rgb, out| out.copy_from_slice(&[rgb[0], rgb[1], rgb[2], 255]));
+pattern_format!(frame::BGRA, |rgb, out| out.copy_from_slice(&[rgb[2], rgb[1], rgb[0], 255]));
+pattern_format!(frame::Luma, |rgb, out| {
+    out[0] = (0.299 * rgb[0] as f32 + 0.587 * rgb[1] as f32 + 0.114 * rgb[2] as f32) as u8
+});
+
+/// A `FrameSource` that draws a `Pattern`.
+pub struct TestPattern<F: PatternFormat> {
+    pattern: Pattern,
+    width: usize,
+    height: usize,
+    pacing: Pacing,
+    target_radius: usize,
+    target_color: [u8; 3],
+    clock: PlayClock,
+    position: Option<usize>,
+    current: Option<Arc<Frame<F>>>,
+    _format: PhantomData<F>,
+}
+
+impl<F: PatternFormat> TestPattern<F> {
+    pub fn new(pattern: Pattern, width: usize, height: usize) -> TestPattern<F> {
+        TestPattern {
+            pattern,
+            width,
+            height,
+            pacing: Pacing::Unpaced,
+            target_radius: width.min(height) / 10,
+            target_color: [0, 255, 0],
+            clock: PlayClock::new(),
+            position: None,
+            current: None,
+            _format: PhantomData,
+        }
+    }
+
+    /// Defaults to `Pacing::Unpaced`, where each call to `get_frame` is a new frame.
+    pub fn pacing(&mut self, pacing: Pacing) -> &mut Self {
+        self.pacing = pacing;
+        self
+    }
+
+    /// Size and color of the `MovingTarget`. Defaults to green, with a radius of a tenth of
+    /// the smaller dimension.
+    pub fn target(&mut self, radius: usize, color: [u8; 3]) -> &mut Self {
+        self.target_radius = radius;
+        self.target_color = color;
+        self.current = None;
+        self
+    }
+
+    /// Where the center of the `MovingTarget` is in the most recent frame, for checking
+    /// locators against.
+    pub fn target_center(&self) -> Option<(f64, f64)> {
+        match self.pattern {
+            Pattern::MovingTarget => Some(self.target_center_at(self.position.unwrap_or(0))),
+            _ => None,
+        }
+    }
+
+    fn target_center_at(&self, position: usize) -> (f64, f64) {
+        let t = position as f64 / TARGET_PERIOD * std::f64::consts::TAU;
+        let rx = (self.width as f64 / 2.0 - self.target_radius as f64 - 1.0).max(0.0);
+        let ry = (self.height as f64 / 2.0 - self.target_radius as f64 - 1.0).max(0.0);
+        // A figure eight, so it moves both ways along each axis.
+        (self.width as f64 / 2.0 + rx * t.cos(), self.height as f64 / 2.0 + ry * (2.0 * t).sin())
+    }
+
+    fn render(&self, position: usize) -> Frame<F> {
+        let bytes = F::byte_count();
+        let mut data = vec![0u8; self.width * self.height * bytes];
+        let target = self.target_center_at(position);
+        let r2 = (self.target_radius * self.target_radius) as f64;
+        for (i, px) in data.chunks_exact_mut(bytes).enumerate() {
+            let x = i % self.width;
+            let y = i / self.width;
+            let rgb = match self.pattern {
+                Pattern::ColorBars => {
+                    const BARS: [[u8; 3]; 8] = [
+                        [255, 255, 255], [255, 255, 0], [0, 255, 255], [0, 255, 0],
+                        [255, 0, 255], [255, 0, 0], [0, 0, 255], [0, 0, 0],
+                    ];
+                    BARS[x * 8 / self.width]
+                }
+                Pattern::Gradient => {
+                    let v = (x * 255 / self.width.saturating_sub(1).max(1)) as u8;
+                    [v, v, v]
+                }
+                Pattern::Checkerboard(size) => {
+                    let size = size.max(1);
+                    if (x / size + y / size).is_multiple_of(2) { [255, 255, 255] } else { [0, 0, 0] }
+                }
+                Pattern::MovingTarget => {
+                    let dx = x as f64 + 0.5 - target.0;
+                    let dy = y as f64 + 0.5 - target.1;
+                    if dx * dx + dy * dy <= r2 { self.target_color } else { [32, 32, 32] }
+                }
+            };
+            F::write(rgb, px);
+        }
+        Frame::new(data, self.width, self.height)
+    }
+}
+
+impl<F: PatternFormat> FrameSource<F> for TestPattern<F> {
+    fn get_frame(&mut self) -> Result<Option<Arc<Frame<F>>>> {
+        let Some(now) = self.clock.now() else {
+            return Ok(None);
+        };
+
+        let position = match self.pacing {
+            Pacing::RealTime(fps) => (now.as_secs_f64() * fps) as usize,
+            Pacing::Unpaced => self.position.map_or(0, |p| p + 1),
+        };
+        // Only the target moves, so anything else can keep handing out the same frame.
+        let stale = self.pattern == Pattern::MovingTarget && self.position != Some(position);
+        if stale || self.current.is_none() {
+            self.current = Some(Arc::new(self.render(position)));
+        }
+        self.position = Some(position);
+        Ok(self.current.clone())
+    }
+
+    fn start(&mut self) -> Result<()> {
+        self.clock.start();
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.clock.stop();
+        Ok(())
+    }
+
+    fn last_frame_id(&self) -> usize {
+        self.position.map_or(0, |p| p + 1)
+    }
+}
@@ -0,0 +1,293 @@
+//! Recording frames to disk, and playing them back with their original timing.
+//!
+//! A recording is two files: the frame data, back to back, at the given path, and an index
+//! next to it with `.idx` tacked on. The index is a header followed by one fixed-size entry per
+//! frame, all little-endian:
+//!
+//! ```text
+//! header: b"VSREC" | version: u8 | byte_count: u32
+//! entry:  frame_id: u64 | unix time in ns: u64 | offset: u64 | len: u32 | width: u32 | height: u32
+//! ```
+
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::camera::FrameSource;
+use crate::error::{Error, Result};
+use crate::file::{PlayClock, Repeat};
+use crate::frame::{Frame, PixelFormat, Pixelate};
+
+const MAGIC: &[u8; 5] = b"VSREC";
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 10;
+const ENTRY_LEN: usize = 36;
+
+fn index_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".idx");
+    PathBuf::from(name)
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    frame_id: u64,
+    timestamp: u64,
+    offset: u64,
+    len: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Entry {
+    fn to_bytes(self) -> [u8; ENTRY_LEN] {
+        let mut out = [0u8; ENTRY_LEN];
+        out[0..8].copy_from_slice(&self.frame_id.to_le_bytes());
+        out[8..16].copy_from_slice(&self.timestamp.to_le_bytes());
+        out[16..24].copy_from_slice(&self.offset.to_le_bytes());
+        out[24..28].copy_from_slice(&self.len.to_le_bytes());
+        out[28..32].copy_from_slice(&self.width.to_le_bytes());
+        out[32..36].copy_from_slice(&self.height.to_le_bytes());
+        out
+    }
+
+    fn from_bytes(b: &[u8]) -> Entry {
+        let u64_at = |i: usize| u64::from_le_bytes(b[i..i + 8].try_into().unwrap());
+        let u32_at = |i: usize| u32::from_le_bytes(b[i..i + 4].try_into().unwrap());
+        Entry {
+            frame_id: u64_at(0),
+            timestamp: u64_at(8),
+            offset: u64_at(16),
+            len: u32_at(24),
+            width: u32_at(28),
+            height: u32_at(32),
+        }
+    }
+}
+
+/// Passes frames through from its source, writing each new one to disk along the way.
+pub struct Recorder<F: PixelFormat, S: FrameSource<F>> {
+    source: S,
+    data: BufWriter<File>,
+    index: BufWriter<File>,
+    offset: u64,
+    last_recorded: Option<usize>,
+    _format: PhantomData<F>,
+}
+
+impl<F: PixelFormat, S: FrameSource<F>> Recorder<F, S> {
+    /// Starts a recording at `path`, replacing any recording already there.
+    pub fn new<P: AsRef<Path>>(source: S, path: P) -> Result<Recorder<F, S>> {
+        let data = BufWriter::new(File::create(path.as_ref())?);
+        let mut index = BufWriter::new(File::create(index_path(path.as_ref()))?);
+        index.write_all(MAGIC)?;
+        index.write_all(&[VERSION])?;
+        index.write_all(&(F::byte_count() as u32).to_le_bytes())?;
+        index.flush()?;
+        Ok(Recorder {
+            source,
+            data,
+            index,
+            offset: 0,
+            last_recorded: None,
+            _format: PhantomData,
+        })
+    }
+
+    pub fn into_inner(self) -> S {
+        self.source
+    }
+
+    fn record(&mut self, frame: &Frame<F>) -> Result<()> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+        let bytes = frame.bytes();
+        let entry = Entry {
+            frame_id: self.source.last_frame_id() as u64,
+            timestamp,
+            offset: self.offset,
+            len: u32::try_from(bytes.len()).map_err(|_| Error::FrameData)?,
+            width: frame.width() as u32,
+            height: frame.height() as u32,
+        };
+        self.data.write_all(bytes)?;
+        // Data goes out before its index entry, so a recording cut off by a brownout still
+        // only indexes whole frames.
+        self.data.flush()?;
+        self.index.write_all(&entry.to_bytes())?;
+        self.index.flush()?;
+        self.offset += bytes.len() as u64;
+        Ok(())
+    }
+}
+
+impl<F: PixelFormat, S: FrameSource<F>> FrameSource<F> for Recorder<F, S> {
+    fn get_frame(&mut self) -> Result<Option<Arc<Frame<F>>>> {
+        let Some(frame) = self.source.get_frame()? else {
+            return Ok(None);
+        };
+        let id = self.source.last_frame_id();
+        if self.last_recorded != Some(id) {
+            self.record(&frame)?;
+            self.last_recorded = Some(id);
+        }
+        Ok(Some(frame))
+    }
+
+    fn start(&mut self) -> Result<()> {
+        self.source.start()
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.source.stop()
+    }
+
+    fn last_frame_id(&self) -> usize {
+        self.source.last_frame_id()
+    }
+
+    fn encoder_quality(&self) -> Option<u8> {
+        self.source.encoder_quality()
+    }
+}
+
+/// Fastest `Playback::speed`, which keeps play time well clear of overflowing a `Duration`.
+pub const MAX_SPEED: f64 = 1000.0;
+
+/// Plays back a recording made by a `Recorder`, with the same time between frames as when
+/// it was recorded.
+pub struct Playback<F: PixelFormat> {
+    data: File,
+    entries: Vec<Entry>,
+    repeat: Repeat,
+    clock: PlayClock,
+    // (loop, entry) of the current frame
+    position: Option<(usize, usize)>,
+    current: Option<Arc<Frame<F>>>,
+}
+
+impl<F: PixelFormat> Playback<F> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Playback<F>> {
+        let data = File::open(path.as_ref())?;
+        let mut index = Vec::new();
+        File::open(index_path(path.as_ref()))?.read_to_end(&mut index)?;
+
+        if index.len() < HEADER_LEN || &index[0..5] != MAGIC || index[5] != VERSION {
+            return Err(Error::CorruptSource);
+        }
+        if u32::from_le_bytes(index[6..10].try_into().unwrap()) as usize != F::byte_count() {
+            return Err(Error::IncompatibleFormat);
+        }
+        // A partial entry at the end means recording stopped mid-write, so it's dropped.
+        let entries: Vec<Entry> = index[HEADER_LEN..].chunks_exact(ENTRY_LEN).map(Entry::from_bytes).collect();
+        if entries.is_empty() {
+            return Err(Error::NoFrameData);
+        }
+
+        Ok(Playback {
+            data,
+            entries,
+            repeat: Repeat::Loop,
+            clock: PlayClock::new(),
+            position: None,
+            current: None,
+        })
+    }
+
+    /// Defaults to `Repeat::Loop`.
+    pub fn repeat(&mut self, repeat: Repeat) -> &mut Self {
+        self.repeat = repeat;
+        self
+    }
+
+    /// Playback speed relative to the original. Defaults to 1.0. 0.0 (or anything negative
+    /// or NaN) pauses, and anything over `MAX_SPEED` is capped.
+    pub fn speed(&mut self, speed: f64) -> &mut Self {
+        let speed = if speed.is_nan() { 0.0 } else { speed.clamp(0.0, MAX_SPEED) };
+        self.clock.set_speed(speed);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Length of one pass through the recording.
+    pub fn duration(&self) -> Duration {
+        let first = self.entries[0].timestamp;
+        let last = self.entries[self.entries.len() - 1].timestamp;
+        Duration::from_nanos(last.saturating_sub(first))
+    }
+
+    /// Frame id, as the recorded source reported it, of the current frame.
+    pub fn recorded_id(&self) -> Option<usize> {
+        self.position.map(|(_, i)| self.entries[i].frame_id as usize)
+    }
+
+    /// When the current frame was originally recorded.
+    pub fn recorded_at(&self) -> Option<SystemTime> {
+        self.position.map(|(_, i)| UNIX_EPOCH + Duration::from_nanos(self.entries[i].timestamp))
+    }
+
+    fn load(&mut self, i: usize) -> Result<Frame<F>> {
+        let entry = self.entries[i];
+        self.data.seek(SeekFrom::Start(entry.offset))?;
+        let mut buf = vec![0; entry.len as usize];
+        self.data.read_exact(&mut buf)?;
+        Ok(Frame::new(buf, entry.width as usize, entry.height as usize))
+    }
+}
+
+impl<F: PixelFormat> FrameSource<F> for Playback<F> {
+    fn get_frame(&mut self) -> Result<Option<Arc<Frame<F>>>> {
+        let Some(now) = self.clock.now() else {
+            return Ok(None);
+        };
+
+        let first = self.entries[0].timestamp;
+        // Spread loops out by the average frame interval, so the last frame doesn't get
+        // skipped right away when looping.
+        let duration = self.duration().as_nanos() as u64;
+        let pass = duration + duration / self.entries.len() as u64;
+        let now = now.as_nanos() as u64;
+        let (lap, now) = match pass {
+            0 => (0, 0),
+            pass => ((now / pass) as usize, now % pass),
+        };
+        if self.repeat == Repeat::Once && lap > 0 {
+            self.current = None;
+            return Ok(None);
+        }
+
+        // Last frame at or before now.
+        let i = self.entries.partition_point(|e| e.timestamp.saturating_sub(first) <= now).max(1) - 1;
+        if self.position != Some((lap, i)) || self.current.is_none() {
+            let frame = self.load(i)?;
+            self.current = Some(Arc::new(frame));
+            self.position = Some((lap, i));
+        }
+        Ok(self.current.clone())
+    }
+
+    fn start(&mut self) -> Result<()> {
+        self.clock.start();
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.clock.stop();
+        Ok(())
+    }
+
+    fn last_frame_id(&self) -> usize {
+        // Keeps counting up through loops, where the recorded ids would start over.
+        let last = self.entries[self.entries.len() - 1].frame_id as usize;
+        self.position.map_or(0, |(lap, i)| lap * (last + 1) + self.entries[i].frame_id as usize)
+    }
+}
@@ -3,8 +3,10 @@ use crate::camera::{FrameSource, Locate, Worker};
 #[cfg(target_os = "linux")]
 use crate::camera::{Camera, CameraConfig};
 use crate::frame::{PixelFormat, Pixelate};
+use crate::encode::{Encodable, Encoding};
 use crate::error::{Result, Error};
 use vistream_protocol::stream::{ClientMessage, Status, Frame as ProtoFrame};
+use vistream_protocol::hello::{HandshakeError, Hello, HelloReader};
 
 use std::net::{TcpListener, SocketAddr, TcpStream};
 use std::io::{self, Write, Read};
@@ -14,6 +16,52 @@ use std::sync::atomic::{Ordering};
 // use std::marker::PhantomData;
 use serde::{Serialize};
 
+// Clients get this long to say hello before they're dropped.
+const HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// A client that's been sent our hello, and hasn't finished sending theirs.
+struct Greeting {
+    socket: TcpStream,
+    addr: SocketAddr,
+    reader: HelloReader,
+    since: std::time::Instant,
+}
+
+impl Greeting {
+    fn start(conn: (TcpStream, SocketAddr), hello: &Hello) -> Option<Greeting> {
+        let (mut socket, addr) = conn;
+        // Their side comes in a bit at a time from the serving loop, so a client that never
+        // says anything can't hold up everyone else.
+        socket.set_nonblocking(true).ok()?;
+        if let Err(e) = hello.write_to(&mut socket) {
+            eprintln!("dropping client {}: {}", addr, e);
+            return None;
+        }
+        Some(Greeting {
+            socket,
+            addr,
+            reader: HelloReader::new(),
+            since: std::time::Instant::now(),
+        })
+    }
+}
+
+/// Moves every client that's finished saying hello into `connections`, and drops the ones
+/// that got it wrong or took too long.
+fn finish_greetings(greetings: &mut Vec<Greeting>, connections: &mut Vec<Connection>, hello: &Hello) {
+    for mut greeting in std::mem::take(greetings) {
+        let res = greeting.reader.poll(&mut greeting.socket)
+            .and_then(|theirs| theirs.map(|theirs| hello.check(theirs)).transpose());
+        match res {
+            Ok(Some(_)) => connections.push(Connection::new((greeting.socket, greeting.addr))),
+            Ok(None) if greeting.since.elapsed() < HANDSHAKE_TIMEOUT => greetings.push(greeting),
+            // Peers from before the handshake existed just sit there.
+            Ok(None) => eprintln!("dropping client {}: {}", greeting.addr, HandshakeError::NotVistream),
+            Err(e) => eprintln!("dropping client {}: {}", greeting.addr, e),
+        }
+    }
+}
+
 // FIXME fix error type later
 pub fn make_response<S: Serialize>(kind: ClientMessage, data: S) -> std::result::Result<Vec<u8>, ()> {
     let mut out = vec![kind.id()];
@@ -111,12 +159,14 @@ impl LocateStream {
             // let socket = TcpListener::bind(addr)?;
             socket.set_nonblocking(true)?;
             let mut connections = Vec::new();
+            let mut greetings = Vec::new();
+            let hello = Hello::new(Vec::new(), Vec::new());
         
             while !kill_flag.load(Ordering::Acquire) {
                 match socket.accept() {
                     Ok(conn) => {
                         println!("connection get! from {}", conn.1);
-                        connections.push(Connection::new(conn));
+                        greetings.extend(Greeting::start(conn, &hello));
                     }
                     Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
                         // do nothing
@@ -125,6 +175,7 @@ impl LocateStream {
                         return Err(e.into());
                     }
                 } 
+                finish_greetings(&mut greetings, &mut connections, &hello);
 
                 // prune connections
                 connections = connections.into_iter().filter(|conn| conn.is_healthy()).collect();
@@ -159,6 +210,7 @@ impl LocateStream {
                                         enabled: conn.is_active(),
                                         healthy: conn.is_healthy(),
                                         framerate: 0.0,
+                                        quality: None,
                                     }).map_err(|_| Error::Unknown)?;
                                     
                                     if let Err(_e) =  conn.write(&resp) {
@@ -206,19 +258,31 @@ pub struct FrameStream{
 }
 
 impl FrameStream {
+    /// Streams frames exactly as the source produces them.
     pub fn launch<S, F>(addr: SocketAddr, source: S) -> Result<FrameStream>
     where S: FrameSource<F> + Send + 'static,
-          F: PixelFormat {
+          F: Encodable {
+        Self::launch_with_encoding(addr, source, F::native_encoding())
+    }
+
+    pub fn launch_with_encoding<S, F>(addr: SocketAddr, source: S, encoding: Encoding) -> Result<FrameStream>
+    where S: FrameSource<F> + Send + 'static,
+          F: Encodable {
+        if !F::supports(encoding) {
+            return Err(Error::UnsupportedEncoding(encoding));
+        }
         let socket = TcpListener::bind(addr)?;
         socket.set_nonblocking(true)?;
+        let hello = Hello::new(F::try_proto_format().into_iter().collect(), vec![encoding]);
         let worker = Worker::spawn(move |kill_flag| {
             let mut source = source;
             let mut connections = Vec::new();
+            let mut greetings = Vec::new();
         
             while !kill_flag.load(Ordering::Acquire) {
                 match socket.accept() {
                     Ok(conn) => {
-                        connections.push(Connection::new(conn));
+                        greetings.extend(Greeting::start(conn, &hello));
                     }
                     Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
                         // do nothing
@@ -227,6 +291,7 @@ impl FrameStream {
                         return Err(e.into());
                     }
                 } 
+                finish_greetings(&mut greetings, &mut connections, &hello);
 
                 // prune connections
                 connections = connections.into_iter().filter(|conn| conn.is_healthy()).collect();
@@ -241,12 +306,27 @@ impl FrameStream {
                 } else {
                     None
                 };
+                let mut data = Vec::new();
+                let frame = match frame {
+                    Some(frame) => match F::encode(&frame, encoding, &mut data) {
+                        Ok(()) => Some(frame),
+                        // One bad frame shouldn't end the stream for everyone.
+                        Err(e) => {
+                            eprintln!("skipping frame that couldn't be encoded: {}", e);
+                            None
+                        }
+                    },
+                    None => None,
+                };
                 let frame_buf = match frame {
                     Some(frame) => {
                         let frame = ProtoFrame {
                             width: frame.width() as u32,
                             height: frame.height() as u32,
-                            data: frame.bytes().clone().into(),
+                            data,
+                            encoding,
+                            keyframe: F::is_keyframe(&frame),
+                            format: F::try_proto_format(),
                         };
 
                         let mut buf = Vec::with_capacity(frame.data.len() + 20); // I don't remember how
@@ -275,6 +355,7 @@ impl FrameStream {
                                         enabled: conn.is_active(),
                                         healthy: conn.is_healthy(),
                                         framerate: 0.0,
+                                        quality: source.encoder_quality(),
                                     }).map_err(|_| Error::Unknown)?;
                                     if let Err(_e) =  conn.write(&resp) {
                                         conn.poison();
@@ -322,7 +403,7 @@ pub struct PassthroughStream {
 #[cfg(target_os = "linux")]
 impl PassthroughStream {
     pub fn launch<F>(addr: SocketAddr, name: &str, cfg: CameraConfig) -> Result<PassthroughStream> 
-    where F: PixelFormat + 'static {
+    where F: Encodable + 'static {
         let cam = Camera::<F>::new(name, cfg)?;
         let stream = FrameStream::launch(addr, cam)?;
         Ok(PassthroughStream {
@@ -1,6 +1,6 @@
 use std::sync::Arc;
 use crate::frame::{Pixelate, PixelFormat, Frame};
-#[cfg(feature = "jpeg")]
+#[cfg(any(feature = "jpeg", feature = "jpeg-rs"))]
 use crate::frame::{MJPG};
 use crate::camera::{FrameSource};
 #[allow(unused_imports)]
@@ -8,80 +8,232 @@ use crate::error::{Result, Error};
 
 use std::marker::PhantomData;
 
-#[cfg(feature = "jpeg")]
-use turbojpeg::{Image, PixelFormat as JpegPixelFormat, Decompressor};
+#[cfg(any(feature = "jpeg", feature = "jpeg-rs"))]
+use crate::jpeg::{JpegEncoder, JpegDecoder, JpegLayout, DefaultEncoder, DefaultDecoder, Subsampling};
+#[cfg(any(feature = "jpeg", feature = "jpeg-rs"))]
+use std::time::Instant;
 
 #[cfg(feature = "jpeg")]
-pub use turbojpeg::{Compressor, Subsamp};
+pub use turbojpeg::{Compressor, Decompressor, Subsamp};
 
+#[cfg(feature = "h264")]
+use crate::frame::H264;
+#[cfg(feature = "h264")]
+use openh264::{OpenH264API, encoder::{Encoder as H264Encoder, EncoderConfig, BitRate, FrameRate, IntraFramePeriod}};
+#[cfg(feature = "h264")]
+use openh264::formats::{YUVBuffer, YUVSlices, YUVSource, RgbSliceU8, BgrSliceU8, RgbaSliceU8, BgraSliceU8};
 
-#[cfg(feature = "jpeg")]
+
+#[cfg(any(feature = "jpeg", feature = "jpeg-rs"))]
 #[allow(dead_code)]
-pub struct JPGSource<F: PixelFormat, S: FrameSource<F>> {
+pub struct JPGSource<F: PixelFormat, S: FrameSource<F>, E: JpegEncoder = DefaultEncoder> {
     source: S,
     last_frame: usize,
-    compressor: Compressor,
+    // Polling faster than the source produces frames gets this back instead of another encode,
+    // which would also throw off the bandwidth budget's idea of the frame interval.
+    out: Option<Arc<Frame<MJPG>>>,
+    encoder: E,
     _format: PhantomData<F>,
     buf: Vec<u8>,
+    // None leaves the encoder's own setting alone.
+    quality: Option<u8>,
+    subsampling: Option<Subsampling>,
+    settings_changed: bool,
+    min_quality: u8,
+    max_quality: u8,
+    adapt_subsampling: bool,
+    budget: Option<Budget>,
 }
 
-#[cfg(feature = "jpeg")]
+// Bandwidth target for adaptive quality, along with running averages of what's actually
+// coming out.
+#[cfg(any(feature = "jpeg", feature = "jpeg-rs"))]
+struct Budget {
+    bytes_per_sec: f64,
+    last_time: Option<Instant>,
+    interval: f64,
+    size: f64,
+}
+
+// Coarsest last. Gray is left out, since dropping color is not a quality tradeoff.
+#[cfg(any(feature = "jpeg", feature = "jpeg-rs"))]
+const SUBSAMPLING_STEPS: [Subsampling; 3] = [Subsampling::None, Subsampling::Sub2x1, Subsampling::Sub2x2];
+
+#[cfg(any(feature = "jpeg", feature = "jpeg-rs"))]
 #[allow(dead_code)]
 impl<F: PixelFormat, S: FrameSource<F>> JPGSource<F, S> {
     pub fn new(source: S) -> JPGSource<F, S> {
-        Self::new_with_compressor(source, Compressor::new().unwrap())
+        Self::new_with_encoder(source, crate::jpeg::default_encoder().unwrap())
+    }
+}
+
+#[cfg(feature = "jpeg")]
+impl<F: PixelFormat, S: FrameSource<F>> JPGSource<F, S, Compressor> {
+    pub fn new_with_compressor(source: S, compressor: Compressor) -> JPGSource<F, S, Compressor> {
+        Self::new_with_encoder(source, compressor)
     }
+}
 
-    pub fn new_with_compressor(source: S, compressor: Compressor) -> JPGSource<F, S> {
+#[cfg(any(feature = "jpeg", feature = "jpeg-rs"))]
+impl<F: PixelFormat, S: FrameSource<F>, E: JpegEncoder> JPGSource<F, S, E> {
+    pub fn new_with_encoder(source: S, encoder: E) -> JPGSource<F, S, E> {
         JPGSource {
             source,
             last_frame: 0,
-            compressor,
+            out: None,
+            encoder,
             buf: Vec::new(),
             _format: PhantomData,
+            quality: None,
+            subsampling: None,
+            settings_changed: false,
+            min_quality: 20,
+            max_quality: 95,
+            adapt_subsampling: false,
+            budget: None,
+        }
+    }
+
+    pub fn encoder(&mut self) -> &mut E {
+        &mut self.encoder
+    }
+
+    /// Fixed quality from 1 to 100. With a bandwidth target, this is just the starting point.
+    pub fn quality(&mut self, quality: u8) -> &mut Self {
+        self.quality = Some(quality.clamp(1, 100));
+        self.settings_changed = true;
+        self
+    }
+
+    pub fn subsampling(&mut self, subsampling: Subsampling) -> &mut Self {
+        self.subsampling = Some(subsampling);
+        self.settings_changed = true;
+        self
+    }
+
+    /// Adjusts quality from frame to frame to keep the output near `bytes_per_sec`.
+    pub fn target_bandwidth(&mut self, bytes_per_sec: usize) -> &mut Self {
+        self.budget = Some(Budget {
+            bytes_per_sec: bytes_per_sec as f64,
+            last_time: None,
+            interval: 0.0,
+            size: 0.0,
+        });
+        self
+    }
+
+    /// Goes back to a fixed quality, keeping whatever quality it was at.
+    pub fn clear_target_bandwidth(&mut self) -> &mut Self {
+        self.budget = None;
+        self
+    }
+
+    /// Bounds for adaptive quality. Defaults to 20 through 95.
+    pub fn quality_range(&mut self, min: u8, max: u8) -> &mut Self {
+        self.min_quality = min.clamp(1, 100);
+        self.max_quality = max.clamp(self.min_quality, 100);
+        self
+    }
+
+    /// Lets adaptive quality also change chroma subsampling once quality hits either end of
+    /// its range.
+    pub fn adapt_subsampling(&mut self, adapt: bool) -> &mut Self {
+        self.adapt_subsampling = adapt;
+        self
+    }
+
+    fn apply_settings(&mut self) -> Result<()> {
+        if self.budget.is_some() && self.quality.is_none() {
+            self.quality = Some(self.max_quality);
+            self.settings_changed = true;
+        }
+        if !self.settings_changed {
+            return Ok(());
+        }
+        if let Some(quality) = self.quality {
+            self.encoder.set_quality(quality)?;
+        }
+        if let Some(subsampling) = self.subsampling {
+            self.encoder.set_subsampling(subsampling)?;
+        }
+        self.settings_changed = false;
+        Ok(())
+    }
+
+    // Nudges quality toward the budget, based on how big the frame that just came out was.
+    fn adapt(&mut self, size: usize) {
+        let Some(budget) = self.budget.as_mut() else {
+            return;
+        };
+
+        let now = Instant::now();
+        if let Some(last) = budget.last_time.replace(now) {
+            let dt = now.duration_since(last).as_secs_f64();
+            budget.interval = if budget.interval == 0.0 { dt } else { 0.8 * budget.interval + 0.2 * dt };
+        }
+        budget.size = if budget.size == 0.0 { size as f64 } else { 0.5 * budget.size + 0.5 * size as f64 };
+        if budget.interval == 0.0 {
+            return;
+        }
+
+        let ratio = budget.size / (budget.bytes_per_sec * budget.interval);
+        let quality = self.quality.unwrap_or(self.max_quality);
+        let new_quality = if ratio > 1.05 {
+            // Back off quickly when over, since that's what actually causes dropped frames.
+            let step = ((ratio - 1.0) * 20.0).ceil().min(10.0) as u8;
+            quality.saturating_sub(step).max(self.min_quality)
+        } else if ratio < 0.85 {
+            (quality + 1).min(self.max_quality)
+        } else {
+            quality
+        };
+
+        if self.adapt_subsampling {
+            let current = self.subsampling.unwrap_or(Subsampling::Sub2x2);
+            let step = SUBSAMPLING_STEPS.iter().position(|s| *s == current);
+            let next = match step {
+                Some(i) if ratio > 1.05 && quality == self.min_quality => SUBSAMPLING_STEPS.get(i + 1),
+                Some(i) if ratio < 0.85 && quality == self.max_quality && i > 0 => SUBSAMPLING_STEPS.get(i - 1),
+                _ => None,
+            };
+            if let Some(next) = next {
+                self.subsampling = Some(*next);
+                self.settings_changed = true;
+            }
+        }
+
+        if new_quality != quality {
+            self.quality = Some(new_quality);
+            self.settings_changed = true;
         }
     }
 }
 
 macro_rules! mjpg_source {
-    ($fmt:ty, $jpeg:expr) => {
-        #[cfg(feature = "jpeg")]
-        impl<S: FrameSource<$fmt>> FrameSource<MJPG> for JPGSource<$fmt, S> {
+    ($fmt:ty, $layout:expr) => {
+        #[cfg(any(feature = "jpeg", feature = "jpeg-rs"))]
+        impl<S: FrameSource<$fmt>, E: JpegEncoder> FrameSource<MJPG> for JPGSource<$fmt, S, E> {
             fn get_frame(&mut self) -> Result<Option<Arc<Frame<MJPG>>>> {
                 let Some(frame) = self.source.get_frame()? else {
                     return Ok(None);
                 };
 
+                let id = self.source.last_frame_id();
+                if self.out.is_some() && id == self.last_frame {
+                    return Ok(self.out.clone());
+                }
 
                 let width = frame.width();
                 let height = frame.height();
 
-                if self.buf.is_empty() {
-                    let len = self.compressor.buf_len(width, height).unwrap();
-                    self.buf.reserve(len);
-                    unsafe {
-                        self.buf.set_len(len);
-                    }
-                }
+                self.apply_settings()?;
+                self.encoder.encode(frame.bytes(), width, height, $layout, &mut self.buf)?;
+                self.adapt(self.buf.len());
 
-                let image: Image<&[u8]> = Image {
-                    pixels: &frame.bytes(),
-                    width,
-                    height,
-                    pitch: frame.width() * <$fmt>::byte_count(),
-                    format: $jpeg,
-                };
-
-                // FIXME this whole allocation for each frame thing ain't great, but I don't
-                // feel like improving it right now.
-                // let Ok(size) = self.compressor.compress_to_slice(image, &mut self.buf) else {
-                //     return Err(Error::Unknown);
-                // };
-                let size = self.compressor.compress_to_slice(image, &mut self.buf).unwrap();
-
-                let out = Frame::new(&self.buf[..size], width, height);
+                let out = Frame::new(&*self.buf, width, height);
                 let out = Arc::new(out);
-                self.last_frame = self.source.last_frame_id();
+                self.out = Some(out.clone());
+                self.last_frame = id;
                 Ok(Some(out))
             }
             fn start(&mut self) -> Result<()> {
@@ -95,38 +247,52 @@ macro_rules! mjpg_source {
             fn last_frame_id(&self) -> usize {
                 self.last_frame
             }
+
+            fn encoder_quality(&self) -> Option<u8> {
+                self.quality
+            }
         }
     }
 }
 
-mjpg_source!(crate::frame::RGB, JpegPixelFormat::RGB);
-mjpg_source!(crate::frame::BGR, JpegPixelFormat::BGR);
-mjpg_source!(crate::frame::RGBA, JpegPixelFormat::RGBA);
-mjpg_source!(crate::frame::BGRA, JpegPixelFormat::BGRA);
-mjpg_source!(crate::frame::Luma, JpegPixelFormat::GRAY);
+mjpg_source!(crate::frame::RGB, JpegLayout::RGB);
+mjpg_source!(crate::frame::BGR, JpegLayout::BGR);
+mjpg_source!(crate::frame::RGBA, JpegLayout::RGBA);
+mjpg_source!(crate::frame::BGRA, JpegLayout::BGRA);
+mjpg_source!(crate::frame::Luma, JpegLayout::Gray);
 
 #[allow(dead_code)]
-#[cfg(feature = "jpeg")]
-pub struct JPGUnpacker<F: PixelFormat, S: FrameSource<MJPG>> {
+#[cfg(any(feature = "jpeg", feature = "jpeg-rs"))]
+pub struct JPGUnpacker<F: PixelFormat, S: FrameSource<MJPG>, D: JpegDecoder = DefaultDecoder> {
     source: S,
     last_frame: usize,
-    decompressor: Decompressor,
+    decoder: D,
     buf: Vec<u8>,
     _format: PhantomData<F>,
 }
 
 #[allow(dead_code)]
-#[cfg(feature = "jpeg")]
+#[cfg(any(feature = "jpeg", feature = "jpeg-rs"))]
 impl<F: PixelFormat, S: FrameSource<MJPG>> JPGUnpacker<F, S> {
     pub fn new(source: S) -> JPGUnpacker<F, S> {
-        Self::new_with_decompressor(source, Decompressor::new().unwrap())
+        Self::new_with_decoder(source, crate::jpeg::default_decoder().unwrap())
+    }
+}
+
+#[cfg(feature = "jpeg")]
+impl<F: PixelFormat, S: FrameSource<MJPG>> JPGUnpacker<F, S, Decompressor> {
+    pub fn new_with_decompressor(source: S, decompressor: Decompressor) -> JPGUnpacker<F, S, Decompressor> {
+        Self::new_with_decoder(source, decompressor)
     }
+}
 
-    pub fn new_with_decompressor(source: S, decompressor: Decompressor) -> JPGUnpacker<F, S> {
+#[cfg(any(feature = "jpeg", feature = "jpeg-rs"))]
+impl<F: PixelFormat, S: FrameSource<MJPG>, D: JpegDecoder> JPGUnpacker<F, S, D> {
+    pub fn new_with_decoder(source: S, decoder: D) -> JPGUnpacker<F, S, D> {
         JPGUnpacker {
             source,
             last_frame: 0,
-            decompressor,
+            decoder,
             buf: Vec::new(),
             _format: PhantomData,
         }
@@ -134,42 +300,190 @@ impl<F: PixelFormat, S: FrameSource<MJPG>> JPGUnpacker<F, S> {
 }
 
 macro_rules! mjpg_unpack {
-    ($jpeg:expr, $fmt:ty) => {
-        #[cfg(feature = "jpeg")]
-        impl<S: FrameSource<MJPG>> FrameSource<$fmt> for JPGUnpacker<$fmt, S> {
+    ($layout:expr, $fmt:ty) => {
+        #[cfg(any(feature = "jpeg", feature = "jpeg-rs"))]
+        impl<S: FrameSource<MJPG>, D: JpegDecoder> FrameSource<$fmt> for JPGUnpacker<$fmt, S, D> {
             fn get_frame(&mut self) -> Result<Option<Arc<Frame<$fmt>>>> {
                 let Some(frame) = self.source.get_frame()? else {
                     return Ok(None);
                 };
 
-
                 let width = frame.width();
                 let height = frame.height();
 
-                if self.buf.is_empty() {
-                    let size = width * height * <$fmt>::byte_count();
-                    self.buf.reserve(size);
-                    unsafe{self.buf.set_len(size)};
-                }
+                self.buf.resize(width * height * <$fmt>::byte_count(), 0);
+                self.decoder.decode(frame.bytes(), $layout, &mut self.buf)?;
+
+                let out = Frame::new(&*self.buf, width, height);
+                let out = Arc::new(out);
+                self.last_frame = self.source.last_frame_id();
+                Ok(Some(out))
+            }
+            fn start(&mut self) -> Result<()> {
+                self.source.start()
+            }
+
+            fn stop(&mut self) -> Result<()> {
+                self.source.stop()
+            }
+
+            fn last_frame_id(&self) -> usize {
+                self.last_frame
+            }
+
+            fn encoder_quality(&self) -> Option<u8> {
+                self.source.encoder_quality()
+            }
+        }
+    }
+}
+
+mjpg_unpack!(JpegLayout::RGB, crate::frame::RGB);
+mjpg_unpack!(JpegLayout::BGR, crate::frame::BGR);
+mjpg_unpack!(JpegLayout::RGBA, crate::frame::RGBA);
+mjpg_unpack!(JpegLayout::BGRA, crate::frame::BGRA);
+mjpg_unpack!(JpegLayout::Gray, crate::frame::Luma);
+
+/// Software H.264 encoder, backed by OpenH264. Each frame coming out is the Annex B byte stream
+/// for one input frame, and only keyframes can be decoded without the frames before them.
+///
+/// Both dimensions of the source must be even.
+#[cfg(feature = "h264")]
+pub struct H264Source<F: PixelFormat, S: FrameSource<F>> {
+    source: S,
+    last_frame: usize,
+    // Source id of the last frame handed to the encoder, and what came out of it. Feeding the
+    // same frame in twice would add a P-frame nobody receives, breaking the reference chain.
+    encoded_id: Option<usize>,
+    out: Option<Arc<Frame<H264>>>,
+    // Built on the first frame, and rebuilt whenever the settings change.
+    encoder: Option<H264Encoder>,
+    bitrate: u32,
+    framerate: f32,
+    keyframe_interval: u32,
+    force_keyframe: bool,
+    yuv: Option<YUVBuffer>,
+    chroma: Vec<u8>,
+    buf: Vec<u8>,
+    _format: PhantomData<F>,
+}
+
+#[cfg(feature = "h264")]
+impl<F: PixelFormat, S: FrameSource<F>> H264Source<F, S> {
+    /// Defaults to 2 Mbps at 30fps, with a keyframe every 60 frames.
+    pub fn new(source: S) -> H264Source<F, S> {
+        H264Source {
+            source,
+            last_frame: 0,
+            encoded_id: None,
+            out: None,
+            encoder: None,
+            bitrate: 2_000_000,
+            framerate: 30.0,
+            keyframe_interval: 60,
+            force_keyframe: false,
+            yuv: None,
+            chroma: Vec::new(),
+            buf: Vec::new(),
+            _format: PhantomData,
+        }
+    }
+
+    /// Target bitrate in bits per second.
+    pub fn bitrate(&mut self, bps: u32) -> &mut Self {
+        self.bitrate = bps;
+        self.encoder = None;
+        self
+    }
+
+    /// Expected framerate, which the rate control uses to spread the bitrate across frames.
+    pub fn framerate(&mut self, fps: f32) -> &mut Self {
+        self.framerate = fps;
+        self.encoder = None;
+        self
+    }
+
+    /// Number of frames between keyframes. 0 leaves it up to the encoder.
+    pub fn keyframe_interval(&mut self, frames: u32) -> &mut Self {
+        self.keyframe_interval = frames;
+        self.encoder = None;
+        self
+    }
+
+    /// Makes the next frame a keyframe, such as when a new client connects.
+    pub fn force_keyframe(&mut self) -> &mut Self {
+        self.force_keyframe = true;
+        self
+    }
+
+    // Returns false if rate control decided to drop the frame.
+    fn encode<Y: YUVSource>(&mut self, yuv: &Y) -> Result<bool> {
+        if self.encoder.is_none() {
+            let interval = match self.keyframe_interval {
+                0 => IntraFramePeriod::auto(),
+                n => IntraFramePeriod::from_num_frames(n),
+            };
+            let config = EncoderConfig::new()
+                .bitrate(BitRate::from_bps(self.bitrate))
+                .max_frame_rate(FrameRate::from_hz(self.framerate))
+                .intra_frame_period(interval);
+            let encoder = H264Encoder::with_api_config(OpenH264API::from_source(), config)
+                .map_err(|_| Error::Unknown)?;
+            self.encoder = Some(encoder);
+        }
+        let encoder = self.encoder.as_mut().unwrap();
+        if self.force_keyframe {
+            encoder.force_intra_frame();
+            self.force_keyframe = false;
+        }
+        let stream = encoder.encode(yuv).map_err(|_| Error::FrameData)?;
+        self.buf.clear();
+        stream.write_vec(&mut self.buf);
+        Ok(!self.buf.is_empty())
+    }
+
+    fn yuv_buffer(&mut self, width: usize, height: usize) -> YUVBuffer {
+        match self.yuv.take() {
+            Some(yuv) if yuv.dimensions() == (width, height) => yuv,
+            _ => YUVBuffer::new(width, height),
+        }
+    }
+}
 
-                let image: Image<&mut [u8]> = Image {
-                    pixels: &mut self.buf,
-                    width,
-                    height,
-                    pitch: frame.width() * <$fmt>::byte_count(),
-                    format: $jpeg,
+macro_rules! h264_source {
+    ($fmt:ty, $slice:ident, $read:ident) => {
+        #[cfg(feature = "h264")]
+        impl<S: FrameSource<$fmt>> FrameSource<H264> for H264Source<$fmt, S> {
+            fn get_frame(&mut self) -> Result<Option<Arc<Frame<H264>>>> {
+                let Some(frame) = self.source.get_frame()? else {
+                    return Ok(None);
                 };
 
-                // FIXME this whole allocation for each frame thing ain't great, but I don't
-                // feel like improving it right now.
-                // let Ok(size) = self.compressor.compress_to_slice(image, &mut self.buf) else {
-                //     return Err(Error::Unknown);
-                // };
-                self.decompressor.decompress(frame.bytes(), image).map_err(|_| Error::FrameData)?;
+                let id = self.source.last_frame_id();
+                if self.encoded_id == Some(id) {
+                    return Ok(self.out.clone());
+                }
+
+                let width = frame.width();
+                let height = frame.height();
+                if width % 2 != 0 || height % 2 != 0 || !frame.is_pixelable() {
+                    return Err(Error::FrameData);
+                }
+
+                let mut yuv = self.yuv_buffer(width, height);
+                yuv.$read($slice::new(frame.bytes(), (width, height)));
+                let res = self.encode(&yuv);
+                self.yuv = Some(yuv);
+                let encoded = res?;
+                self.encoded_id = Some(id);
+                if !encoded {
+                    return Ok(self.out.clone());
+                }
 
                 let out = Frame::new(&*self.buf, width, height);
                 let out = Arc::new(out);
-                self.last_frame = self.source.last_frame_id();
+                self.out = Some(out.clone());
+                self.last_frame = id;
                 Ok(Some(out))
             }
             fn start(&mut self) -> Result<()> {
@@ -183,15 +497,71 @@ macro_rules! mjpg_unpack {
             fn last_frame_id(&self) -> usize {
                 self.last_frame
             }
+
+            fn encoder_quality(&self) -> Option<u8> {
+                self.source.encoder_quality()
+            }
         }
     }
 }
 
-mjpg_unpack!(JpegPixelFormat::RGB, crate::frame::RGB);
-mjpg_unpack!(JpegPixelFormat::BGR, crate::frame::BGR);
-mjpg_unpack!(JpegPixelFormat::RGBA, crate::frame::RGBA);
-mjpg_unpack!(JpegPixelFormat::BGRA, crate::frame::BGRA);
-mjpg_unpack!(JpegPixelFormat::GRAY, crate::frame::Luma);
+h264_source!(crate::frame::RGB, RgbSliceU8, read_rgb8);
+h264_source!(crate::frame::BGR, BgrSliceU8, read_rgb);
+h264_source!(crate::frame::RGBA, RgbaSliceU8, read_rgba8);
+h264_source!(crate::frame::BGRA, BgraSliceU8, read_bgra8);
+
+#[cfg(feature = "h264")]
+impl<S: FrameSource<Luma>> FrameSource<H264> for H264Source<Luma, S> {
+    fn get_frame(&mut self) -> Result<Option<Arc<Frame<H264>>>> {
+        let Some(frame) = self.source.get_frame()? else {
+            return Ok(None);
+        };
+
+        let id = self.source.last_frame_id();
+        if self.encoded_id == Some(id) {
+            return Ok(self.out.clone());
+        }
+
+        let width = frame.width();
+        let height = frame.height();
+        if width % 2 != 0 || height % 2 != 0 || !frame.is_pixelable() {
+            return Err(Error::FrameData);
+        }
+
+        // Luma is already the Y plane, so it only needs some neutral chroma to go with it.
+        let mut chroma = std::mem::take(&mut self.chroma);
+        chroma.resize(width * height / 4, 128);
+        let yuv = YUVSlices::new((frame.bytes(), &chroma, &chroma), (width, height), (width, width / 2, width / 2));
+        let res = self.encode(&yuv);
+        self.chroma = chroma;
+        let encoded = res?;
+        self.encoded_id = Some(id);
+        if !encoded {
+            return Ok(self.out.clone());
+        }
+
+        let out = Frame::new(&*self.buf, width, height);
+        let out = Arc::new(out);
+        self.out = Some(out.clone());
+        self.last_frame = id;
+        Ok(Some(out))
+    }
+    fn start(&mut self) -> Result<()> {
+        self.source.start()
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.source.stop()
+    }
+
+    fn last_frame_id(&self) -> usize {
+        self.last_frame
+    }
+
+    fn encoder_quality(&self) -> Option<u8> {
+        self.source.encoder_quality()
+    }
+}
 
 pub enum Rotation {
     Clockwise90,
@@ -269,6 +639,10 @@ impl<F: PixelFormat, S: FrameSource<F>> FrameSource<F> for Rotate<F, S> {
     fn last_frame_id(&self) -> usize {
         self.source.last_frame_id()
     }
+
+    fn encoder_quality(&self) -> Option<u8> {
+        self.source.encoder_quality()
+    }
 }
 
 pub enum Reflection {
@@ -321,10 +695,15 @@ impl<F: PixelFormat, S: FrameSource<F>> FrameSource<F> for Reflect<F, S> {
     fn last_frame_id(&self) -> usize {
         self.source.last_frame_id()
     }
+
+    fn encoder_quality(&self) -> Option<u8> {
+        self.source.encoder_quality()
+    }
 }
 
 pub struct Convert<F: PixelFormat, T: PixelFormat, S: FrameSource<F>> {
     source: S,
+    demosaic: Demosaic,
     _from_format: PhantomData<F>,
     _to_format: PhantomData<T>,
 }
@@ -333,13 +712,14 @@ impl<F: PixelFormat, T: PixelFormat, S: FrameSource<F>> Convert<F, T, S> {
     pub fn new(source: S) -> Convert<F, T, S> {
         Convert {
             source,
+            demosaic: Demosaic::Bilinear,
             _from_format: PhantomData,
             _to_format: PhantomData,
         }
     }
 }
 
-use crate::frame::{RGB, Luma};
+use crate::frame::{RGB, Luma, SRGGB8, SRGGB10, SRGGB12, SRGGB10P, SRGGB12P, SRGGB16};
 impl<S: FrameSource<RGB>> FrameSource<Luma> for Convert<RGB, Luma, S> {
     fn get_frame(&mut self) -> Result<Option<Arc<Frame<Luma>>>> {
         let Some(frame) = self.source.get_frame()?  else {
@@ -375,4 +755,543 @@ impl<S: FrameSource<RGB>> FrameSource<Luma> for Convert<RGB, Luma, S> {
     fn last_frame_id(&self) -> usize {
         self.source.last_frame_id()
     }
+
+    fn encoder_quality(&self) -> Option<u8> {
+        self.source.encoder_quality()
+    }
+}
+
+/// Formats with one byte per channel, so `Lut` can map them byte by byte. Anything wider,
+/// packed or compressed would come out garbled.
+pub trait LutFormat: PixelFormat {}
+
+impl LutFormat for RGB {}
+impl LutFormat for crate::frame::BGR {}
+impl LutFormat for crate::frame::RGBA {}
+impl LutFormat for crate::frame::BGRA {}
+impl LutFormat for Luma {}
+
+enum LutTables {
+    Fixed(Vec<[u8; 256]>),
+    Equalize,
+}
+
+/// Maps every byte of a frame through a 256-entry table, one table per channel.
+pub struct Lut<F: LutFormat, S: FrameSource<F>> {
+    source: S,
+    tables: LutTables,
+    _format: PhantomData<F>,
 }
+
+impl<F: LutFormat, S: FrameSource<F>> Lut<F, S> {
+    /// Applies the same table to every channel.
+    pub fn new(source: S, table: [u8; 256]) -> Lut<F, S> {
+        Self::per_channel(source, vec![table; F::byte_count()])
+    }
+
+    /// Applies `tables[i]` to channel `i`. Channels without a table are left untouched.
+    pub fn per_channel(source: S, tables: Vec<[u8; 256]>) -> Lut<F, S> {
+        let mut tables = tables;
+        tables.truncate(F::byte_count());
+        while tables.len() < F::byte_count() {
+            tables.push(identity_table());
+        }
+        Lut {
+            source,
+            tables: LutTables::Fixed(tables),
+            _format: PhantomData,
+        }
+    }
+
+    /// `out = 255 * (in / 255)^(1 / gamma)`, so values above 1 brighten shadows.
+    pub fn gamma(source: S, gamma: f64) -> Lut<F, S> {
+        let exponent = gamma.recip();
+        Self::new(source, make_table(|v| 255.0 * (v / 255.0).powf(exponent)))
+    }
+
+    /// `out = contrast * (in - 128) + 128 + brightness`, clamped to the valid range.
+    pub fn brightness_contrast(source: S, brightness: f64, contrast: f64) -> Lut<F, S> {
+        Self::new(source, make_table(|v| contrast * (v - 128.0) + 128.0 + brightness))
+    }
+
+    /// Global histogram equalization, recomputed from every frame, independently per channel.
+    pub fn equalize(source: S) -> Lut<F, S> {
+        Lut {
+            source,
+            tables: LutTables::Equalize,
+            _format: PhantomData,
+        }
+    }
+}
+
+fn identity_table() -> [u8; 256] {
+    std::array::from_fn(|i| i as u8)
+}
+
+fn make_table<M: Fn(f64) -> f64>(map: M) -> [u8; 256] {
+    std::array::from_fn(|i| map(i as f64).round().clamp(0.0, 255.0) as u8)
+}
+
+// Maps a histogram to the table that flattens it.
+fn equalize_table(histogram: &[u32; 256]) -> [u8; 256] {
+    let total: u32 = histogram.iter().sum();
+    let first = histogram.iter().copied().find(|&n| n != 0).unwrap_or(0);
+    if total == first {
+        return identity_table();
+    }
+    let scale = 255.0 / (total - first) as f64;
+    let mut sum = 0;
+    std::array::from_fn(|i| {
+        sum += histogram[i];
+        (sum.saturating_sub(first) as f64 * scale).round() as u8
+    })
+}
+
+impl<F: LutFormat, S: FrameSource<F>> FrameSource<F> for Lut<F, S> {
+    fn get_frame(&mut self) -> Result<Option<Arc<Frame<F>>>> {
+        let Some(frame) = self.source.get_frame()? else {
+            return Ok(None);
+        };
+        if !frame.is_pixelable() {
+            return Err(Error::FrameData);
+        }
+
+        let channels = F::byte_count();
+        let equalized;
+        let tables = match self.tables {
+            LutTables::Fixed(ref tables) => tables,
+            LutTables::Equalize => {
+                let mut histograms = vec![[0u32; 256]; channels];
+                for (i, &v) in frame.bytes().iter().enumerate() {
+                    histograms[i % channels][v as usize] += 1;
+                }
+                equalized = histograms.iter().map(equalize_table).collect::<Vec<_>>();
+                &equalized
+            }
+        };
+
+        let data = frame.bytes().iter()
+            .enumerate()
+            .map(|(i, &v)| tables[i % channels][v as usize])
+            .collect::<Vec<u8>>();
+
+        Ok(Some(Arc::new(Frame::new(data, frame.width(), frame.height()))))
+    }
+
+    fn start(&mut self) -> Result<()> {
+        self.source.start()
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.source.stop()
+    }
+
+    fn last_frame_id(&self) -> usize {
+        self.source.last_frame_id()
+    }
+
+    fn encoder_quality(&self) -> Option<u8> {
+        self.source.encoder_quality()
+    }
+}
+
+/// Contrast-limited adaptive histogram equalization. The frame is split into a grid of
+/// tiles, each equalized on its own with a capped histogram, and the per-tile tables are
+/// blended bilinearly so tile edges don't show.
+pub struct Clahe<S: FrameSource<Luma>> {
+    source: S,
+    tiles_x: usize,
+    tiles_y: usize,
+    clip_limit: f64,
+}
+
+impl<S: FrameSource<Luma>> Clahe<S> {
+    /// Uses an 8x8 grid of tiles and a clip limit of 2.
+    pub fn new(source: S) -> Clahe<S> {
+        Self::new_with_params(source, 8, 8, 2.0)
+    }
+
+    /// `clip_limit` is relative to a flat histogram: a limit of 1 allows no more than the
+    /// average count in any bin, larger values allow more contrast.
+    pub fn new_with_params(source: S, tiles_x: usize, tiles_y: usize, clip_limit: f64) -> Clahe<S> {
+        Clahe {
+            source,
+            tiles_x: tiles_x.max(1),
+            tiles_y: tiles_y.max(1),
+            clip_limit,
+        }
+    }
+
+    fn tile_table(&self, frame: &Frame<Luma>, x0: usize, x1: usize, y0: usize, y1: usize) -> [u8; 256] {
+        let width = frame.width();
+        let bytes = frame.bytes();
+        let mut histogram = [0u32; 256];
+        for y in y0..y1 {
+            for &v in &bytes[y * width + x0..y * width + x1] {
+                histogram[v as usize] += 1;
+            }
+        }
+
+        let count = ((x1 - x0) * (y1 - y0)) as u32;
+        if count == 0 {
+            return identity_table();
+        }
+        let limit = ((self.clip_limit * count as f64 / 256.0) as u32).max(1);
+        let mut excess = 0;
+        for bin in histogram.iter_mut() {
+            if *bin > limit {
+                excess += *bin - limit;
+                *bin = limit;
+            }
+        }
+        let share = excess / 256;
+        let remainder = (excess % 256) as usize;
+        for bin in histogram.iter_mut() {
+            *bin += share;
+        }
+        // spread what's left evenly, rather than piling it into the darkest bins
+        if let Some(step) = 256usize.checked_div(remainder) {
+            for bin in histogram.iter_mut().step_by(step).take(remainder) {
+                *bin += 1;
+            }
+        }
+
+        let scale = 255.0 / count as f64;
+        let mut sum = 0;
+        std::array::from_fn(|i| {
+            sum += histogram[i];
+            (sum as f64 * scale).round().min(255.0) as u8
+        })
+    }
+}
+
+impl<S: FrameSource<Luma>> FrameSource<Luma> for Clahe<S> {
+    fn get_frame(&mut self) -> Result<Option<Arc<Frame<Luma>>>> {
+        let Some(frame) = self.source.get_frame()? else {
+            return Ok(None);
+        };
+        if !frame.is_pixelable() {
+            return Err(Error::FrameData);
+        }
+
+        let width = frame.width();
+        let height = frame.height();
+        let tiles_x = self.tiles_x.min(width.max(1));
+        let tiles_y = self.tiles_y.min(height.max(1));
+        let bound = |tile: usize, tiles: usize, len: usize| tile * len / tiles;
+
+        let mut tables = Vec::with_capacity(tiles_x * tiles_y);
+        for ty in 0..tiles_y {
+            for tx in 0..tiles_x {
+                tables.push(self.tile_table(
+                    &frame,
+                    bound(tx, tiles_x, width), bound(tx + 1, tiles_x, width),
+                    bound(ty, tiles_y, height), bound(ty + 1, tiles_y, height),
+                ));
+            }
+        }
+
+        // Position of a pixel in "tile center" coordinates, split into the tile before it
+        // and the weight given to the tile after it.
+        let locate = |p: usize, tiles: usize, len: usize| {
+            let t = ((p as f64 + 0.5) * tiles as f64 / len as f64 - 0.5).max(0.0);
+            let lo = (t as usize).min(tiles - 1);
+            let hi = (lo + 1).min(tiles - 1);
+            (lo, hi, t - lo as f64)
+        };
+
+        let bytes = frame.bytes();
+        let mut data = vec![0u8; width * height];
+        for y in 0..height {
+            let (ty0, ty1, wy) = locate(y, tiles_y, height);
+            for x in 0..width {
+                let (tx0, tx1, wx) = locate(x, tiles_x, width);
+                let v = bytes[y * width + x] as usize;
+                let at = |tx: usize, ty: usize| tables[ty * tiles_x + tx][v] as f64;
+                let top = at(tx0, ty0) * (1.0 - wx) + at(tx1, ty0) * wx;
+                let bottom = at(tx0, ty1) * (1.0 - wx) + at(tx1, ty1) * wx;
+                data[y * width + x] = (top * (1.0 - wy) + bottom * wy).round() as u8;
+            }
+        }
+
+        Ok(Some(Arc::new(Frame::new(data, width, height))))
+    }
+
+    fn start(&mut self) -> Result<()> {
+        self.source.start()
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.source.stop()
+    }
+
+    fn last_frame_id(&self) -> usize {
+        self.source.last_frame_id()
+    }
+
+    fn encoder_quality(&self) -> Option<u8> {
+        self.source.encoder_quality()
+    }
+}
+
+/// Unpacks any of the raw Bayer formats into `SRGGB16`, so the sample layout and range
+/// are the same no matter what the sensor produced.
+pub struct Unpack<F: PixelFormat, S: FrameSource<F>> {
+    source: S,
+    _format: PhantomData<F>,
+}
+
+impl<F: PixelFormat, S: FrameSource<F>> Unpack<F, S> {
+    pub fn new(source: S) -> Unpack<F, S> {
+        Unpack {
+            source,
+            _format: PhantomData,
+        }
+    }
+}
+
+// Each unpacker fills one row of 16-bit samples from one row of source bytes.
+fn unpack_8(row: &[u8], out: &mut [u16]) {
+    for (o, &b) in out.iter_mut().zip(row) {
+        *o = (b as u16) << 8;
+    }
+}
+
+fn unpack_16<const BITS: u32>(row: &[u8], out: &mut [u16]) {
+    for (o, b) in out.iter_mut().zip(row.chunks_exact(2)) {
+        *o = u16::from_le_bytes([b[0], b[1]]) << (16 - BITS);
+    }
+}
+
+// CSI-2 10-bit: four bytes holding the high bits of four samples, then one byte holding
+// the two low bits of each.
+fn unpack_csi2p_10(row: &[u8], out: &mut [u16]) {
+    for (i, o) in out.iter_mut().enumerate() {
+        let group = &row[i / 4 * 5..];
+        let low = (group[4] >> ((i % 4) * 2)) & 0x03;
+        *o = (((group[i % 4] as u16) << 2) | low as u16) << 6;
+    }
+}
+
+// CSI-2 12-bit: two bytes holding the high bits of two samples, then one byte holding the
+// four low bits of each.
+fn unpack_csi2p_12(row: &[u8], out: &mut [u16]) {
+    for (i, o) in out.iter_mut().enumerate() {
+        let group = &row[i / 2 * 3..];
+        let low = (group[2] >> ((i % 2) * 4)) & 0x0f;
+        *o = (((group[i % 2] as u16) << 4) | low as u16) << 4;
+    }
+}
+
+macro_rules! bayer_unpack {
+    ($fmt:ty, $unpack:expr, $row_bytes:expr) => {
+        impl<S: FrameSource<$fmt>> FrameSource<SRGGB16> for Unpack<$fmt, S> {
+            fn get_frame(&mut self) -> Result<Option<Arc<Frame<SRGGB16>>>> {
+                let Some(frame) = self.source.get_frame()? else {
+                    return Ok(None);
+                };
+
+                let width = frame.width();
+                let height = frame.height();
+                let bytes = frame.bytes();
+                let row_bytes: usize = $row_bytes(width);
+                if height == 0 {
+                    return Ok(Some(Arc::new(Frame::new(Vec::new(), width, height))));
+                }
+                // The camera may pad rows out to some alignment, and doesn't say by how much,
+                // so work it out from the total size.
+                let stride = bytes.len() / height;
+                if stride < row_bytes {
+                    return Err(Error::FrameData);
+                }
+
+                let mut samples = vec![0u16; width];
+                let mut data = Vec::with_capacity(width * height * 2);
+                for row in bytes.chunks_exact(stride).take(height) {
+                    $unpack(&row[..row_bytes], &mut samples);
+                    data.extend(samples.iter().flat_map(|s| s.to_le_bytes()));
+                }
+
+                Ok(Some(Arc::new(Frame::new(data, width, height))))
+            }
+
+            fn start(&mut self) -> Result<()> {
+                self.source.start()
+            }
+
+            fn stop(&mut self) -> Result<()> {
+                self.source.stop()
+            }
+
+            fn last_frame_id(&self) -> usize {
+                self.source.last_frame_id()
+            }
+
+            fn encoder_quality(&self) -> Option<u8> {
+                self.source.encoder_quality()
+            }
+        }
+    }
+}
+
+bayer_unpack!(SRGGB8, unpack_8, |w: usize| w);
+bayer_unpack!(SRGGB10, unpack_16::<10>, |w: usize| w * 2);
+bayer_unpack!(SRGGB12, unpack_16::<12>, |w: usize| w * 2);
+bayer_unpack!(SRGGB10P, unpack_csi2p_10, |w: usize| w.div_ceil(4) * 5);
+bayer_unpack!(SRGGB12P, unpack_csi2p_12, |w: usize| w.div_ceil(2) * 3);
+
+/// How missing color samples are filled in when converting Bayer frames to RGB.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Demosaic {
+    /// Averages the nearest samples of each color. Fast, but smears color across edges.
+    Bilinear,
+    /// Interpolates green along whichever axis has the weaker gradient, then fills red
+    /// and blue from color differences. Noticeably sharper, for a bit more work.
+    EdgeAware,
+}
+
+impl<F: PixelFormat, S: FrameSource<F>> Convert<F, RGB, S> {
+    /// Only meaningful for the Bayer conversions. Everything else ignores it.
+    pub fn with_demosaic(source: S, demosaic: Demosaic) -> Convert<F, RGB, S> {
+        Convert {
+            source,
+            demosaic,
+            _from_format: PhantomData,
+            _to_format: PhantomData,
+        }
+    }
+}
+
+// Reflects a coordinate back into [0, len) while keeping its parity, so a mirrored sample is
+// always the same color as the one it stands in for.
+fn reflect(i: isize, len: usize) -> usize {
+    let len = len as isize;
+    let i = if i < 0 { -i } else { i };
+    let i = if i >= len { 2 * (len - 1) - i } else { i };
+    i.clamp(0, len - 1) as usize
+}
+
+// Bayer RGGB: red on even rows and columns, blue on odd rows and columns, green elsewhere.
+fn demosaic_rggb<P: Fn(usize, usize) -> f32>(width: usize, height: usize, sample: P, method: Demosaic) -> Vec<u8> {
+    let at = |x: usize, y: usize, dx: isize, dy: isize| {
+        sample(reflect(x as isize + dx, width), reflect(y as isize + dy, height))
+    };
+    let cross = |x, y| (at(x, y, -1, 0) + at(x, y, 1, 0) + at(x, y, 0, -1) + at(x, y, 0, 1)) / 4.0;
+    let diagonal = |x, y| (at(x, y, -1, -1) + at(x, y, 1, -1) + at(x, y, -1, 1) + at(x, y, 1, 1)) / 4.0;
+    let horizontal = |x, y| (at(x, y, -1, 0) + at(x, y, 1, 0)) / 2.0;
+    let vertical = |x, y| (at(x, y, 0, -1) + at(x, y, 0, 1)) / 2.0;
+    let is_green = |x: usize, y: usize| (x + y) % 2 == 1;
+
+    let mut rgb = vec![[0f32; 3]; width * height];
+    match method {
+        Demosaic::Bilinear => {
+            for y in 0..height {
+                for x in 0..width {
+                    let here = at(x, y, 0, 0);
+                    rgb[y * width + x] = match (y % 2, x % 2) {
+                        (0, 0) => [here, cross(x, y), diagonal(x, y)],
+                        (0, _) => [horizontal(x, y), here, vertical(x, y)],
+                        (_, 0) => [vertical(x, y), here, horizontal(x, y)],
+                        _ => [diagonal(x, y), cross(x, y), here],
+                    };
+                }
+            }
+        }
+        Demosaic::EdgeAware => {
+            // Green first, along the smoother direction, with a second-derivative correction
+            // from the site's own color channel.
+            let mut green = vec![0f32; width * height];
+            for y in 0..height {
+                for x in 0..width {
+                    let here = at(x, y, 0, 0);
+                    green[y * width + x] = if is_green(x, y) {
+                        here
+                    } else {
+                        let dh = (at(x, y, -1, 0) - at(x, y, 1, 0)).abs()
+                            + (2.0 * here - at(x, y, -2, 0) - at(x, y, 2, 0)).abs();
+                        let dv = (at(x, y, 0, -1) - at(x, y, 0, 1)).abs()
+                            + (2.0 * here - at(x, y, 0, -2) - at(x, y, 0, 2)).abs();
+                        let gh = horizontal(x, y) + (2.0 * here - at(x, y, -2, 0) - at(x, y, 2, 0)) / 4.0;
+                        let gv = vertical(x, y) + (2.0 * here - at(x, y, 0, -2) - at(x, y, 0, 2)) / 4.0;
+                        if dh < dv {
+                            gh
+                        } else if dv < dh {
+                            gv
+                        } else {
+                            (gh + gv) / 2.0
+                        }
+                    };
+                }
+            }
+
+            // Then red and blue, by interpolating their difference from green, which varies
+            // much more slowly across an edge than the colors themselves do.
+            let g = |x: usize, y: usize, dx: isize, dy: isize| {
+                green[reflect(y as isize + dy, height) * width + reflect(x as isize + dx, width)]
+            };
+            let diff = |x: usize, y: usize, dx: isize, dy: isize| at(x, y, dx, dy) - g(x, y, dx, dy);
+            for y in 0..height {
+                for x in 0..width {
+                    let here = at(x, y, 0, 0);
+                    let gr = g(x, y, 0, 0);
+                    let h = (diff(x, y, -1, 0) + diff(x, y, 1, 0)) / 2.0;
+                    let v = (diff(x, y, 0, -1) + diff(x, y, 0, 1)) / 2.0;
+                    let d = (diff(x, y, -1, -1) + diff(x, y, 1, -1) + diff(x, y, -1, 1) + diff(x, y, 1, 1)) / 4.0;
+                    rgb[y * width + x] = match (y % 2, x % 2) {
+                        (0, 0) => [here, gr, gr + d],
+                        (0, _) => [gr + h, gr, gr + v],
+                        (_, 0) => [gr + v, gr, gr + h],
+                        _ => [gr + d, gr, here],
+                    };
+                }
+            }
+        }
+    }
+
+    rgb.iter()
+        .flat_map(|p| p.map(|c| c.round().clamp(0.0, 255.0) as u8))
+        .collect()
+}
+
+macro_rules! bayer_demosaic {
+    ($fmt:ty, $sample:expr) => {
+        impl<S: FrameSource<$fmt>> FrameSource<RGB> for Convert<$fmt, RGB, S> {
+            fn get_frame(&mut self) -> Result<Option<Arc<Frame<RGB>>>> {
+                let Some(frame) = self.source.get_frame()? else {
+                    return Ok(None);
+                };
+                if !frame.is_pixelable() {
+                    return Err(Error::FrameData);
+                }
+
+                let width = frame.width();
+                let height = frame.height();
+                let bytes = frame.bytes();
+                let data = demosaic_rggb(width, height, |x, y| $sample(bytes, y * width + x), self.demosaic);
+
+                Ok(Some(Arc::new(Frame::new(data, width, height))))
+            }
+
+            fn start(&mut self) -> Result<()> {
+                self.source.start()
+            }
+
+            fn stop(&mut self) -> Result<()> {
+                self.source.stop()
+            }
+
+            fn last_frame_id(&self) -> usize {
+                self.source.last_frame_id()
+            }
+
+            fn encoder_quality(&self) -> Option<u8> {
+                self.source.encoder_quality()
+            }
+        }
+    }
+}
+
+bayer_demosaic!(SRGGB8, |bytes: &[u8], i: usize| bytes[i] as f32);
+bayer_demosaic!(SRGGB16, |bytes: &[u8], i: usize| {
+    u16::from_le_bytes([bytes[i * 2], bytes[i * 2 + 1]]) as f32 / 256.0
+});
@@ -0,0 +1,159 @@
+use crate::camera::{Worker, FrameSource};
+use crate::frame::{Frame, PixelFormat, MJPG};
+use crate::error::{Result, Error};
+use crate::encode::Encodable;
+use vistream_protocol::stream::{ClientMessage, Frame as ProtoFrame, Encoding};
+
+use std::io;
+use std::net::TcpStream;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{Ordering, AtomicUsize};
+use std::sync::mpsc::{self, Sender};
+
+use tungstenite as ws;
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::WebSocket;
+
+/// The WebSocket counterpart to `client::FrameClient`, for pulling frames from a
+/// `WSFrameStream`, including through proxies that only pass HTTP. Checks the format and
+/// encoding of incoming frames the same way.
+pub struct WSFrameClient<F: PixelFormat = MJPG> {
+    worker: Worker,
+    // The worker owns the socket, so control messages go through it.
+    control: Sender<ClientMessage>,
+    last_frame: Arc<RwLock<Option<Arc<Frame<F>>>>>,
+    last_frame_id: Arc<AtomicUsize>,
+    // encoding and keyframe flag of the last frame
+    last_meta: Arc<RwLock<(Encoding, bool)>>,
+}
+
+impl<F: Encodable + Send + Sync + 'static> WSFrameClient<F> {
+    /// Connects to a `ws://` url, like `ws://10.0.0.2:5800` or `ws://robot.local/camera`.
+    pub fn connect(url: &str) -> Result<WSFrameClient<F>> {
+        let (socket, _) = ws::connect(url).map_err(|_| Error::Handshake)?;
+        if let MaybeTlsStream::Plain(stream) = socket.get_ref() {
+            // Short enough that control messages go out promptly.
+            stream.set_read_timeout(Some(std::time::Duration::from_millis(10)))?;
+        }
+
+        let (control, commands) = mpsc::channel::<ClientMessage>();
+        let last_frame = Arc::new(RwLock::new(None));
+        let last_frame_id = Arc::new(AtomicUsize::new(0));
+        let last_meta = Arc::new(RwLock::new((Encoding::Jpeg, true)));
+        let worker_frame = last_frame.clone();
+        let worker_frame_id = last_frame_id.clone();
+        let worker_meta = last_meta.clone();
+        let worker = Worker::spawn(move |kill_flag| {
+            let mut socket: WebSocket<MaybeTlsStream<TcpStream>> = socket;
+
+            let res = (|| -> Result<()> {
+                while !kill_flag.load(Ordering::Acquire) {
+                    while let Ok(msg) = commands.try_recv() {
+                        socket.send(ws::Message::Binary(vec![msg.id()].into()))?;
+                    }
+
+                    let data = match socket.read() {
+                        Ok(ws::Message::Binary(data)) => data,
+                        Ok(ws::Message::Close(_)) => return Err(Error::Server("stream closed".into())),
+                        // status replies and pings
+                        Ok(_) => continue,
+                        Err(ws::Error::Io(e)) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                            continue;
+                        }
+                        Err(e) => return Err(e.into()),
+                    };
+                    let proto_frame: ProtoFrame = rmp_serde::from_slice(&data)?;
+                    if proto_frame.format.is_some_and(|format| Some(format) != F::try_proto_format()) {
+                        return Err(Error::IncompatibleFormat);
+                    }
+                    if proto_frame.encoding != F::native_encoding() {
+                        return Err(Error::UnsupportedEncoding(proto_frame.encoding));
+                    }
+                    let frame = Frame::new(proto_frame.data, proto_frame.width as usize, proto_frame.height as usize);
+                    *worker_meta.write().unwrap() = (proto_frame.encoding, proto_frame.keyframe);
+                    *worker_frame.write().unwrap() = Some(Arc::new(frame));
+                    worker_frame_id.fetch_add(1, Ordering::AcqRel);
+                }
+                Ok(())
+            })();
+
+            // Say goodbye either way. If the connection is already gone, there's no one to
+            // say it to.
+            let _ = socket.send(ws::Message::Binary(vec![ClientMessage::Disconnect.id()].into()));
+            let _ = socket.close(None);
+            let _ = socket.flush();
+            res
+        });
+
+        Ok(WSFrameClient {
+            worker,
+            control,
+            last_frame,
+            last_frame_id,
+            last_meta,
+        })
+    }
+}
+
+impl<F: PixelFormat> WSFrameClient<F> {
+    /// How the data of the most recent frame is encoded. See `FrameClient::encoding`.
+    pub fn encoding(&self) -> Encoding {
+        self.last_meta.read().map(|m| m.0).unwrap_or_default()
+    }
+
+    /// Whether the most recent frame can be decoded on its own. Always true for anything but
+    /// H264, which needs to start decoding (or forwarding) from a keyframe.
+    pub fn is_keyframe(&self) -> bool {
+        self.last_meta.read().map(|m| m.1).unwrap_or(true)
+    }
+
+    fn send(&self, msg: ClientMessage) -> Result<()> {
+        self.control.send(msg).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe).into())
+    }
+}
+
+impl<F: PixelFormat> Drop for WSFrameClient<F> {
+    fn drop(&mut self) {
+        self.worker.join();
+    }
+}
+
+impl<F: PixelFormat> FrameSource<F> for WSFrameClient<F> {
+    fn get_frame(&mut self) -> Result<Option<Arc<Frame<F>>>> {
+        // If the worker has stopped for any reason, return nothing, except for the first
+        // time, where it returns the error causing it.
+        if self.worker.is_finished() {
+            if self.worker.is_joinable() {
+                return match self.worker.join() {
+                    Some(e) => Err(e),
+                    None => Ok(None),
+                }
+            }
+            return Ok(None);
+        }
+
+        match self.last_frame.read() {
+            Ok(guard) => Ok(guard.clone()),
+            Err(_) => {
+                // The lock has been poisoned, so the worker panicked.
+                self.worker.kill();
+                match self.worker.join() {
+                    Some(e) => Err(e),
+                    None => Ok(None),
+                }
+            }
+        }
+    }
+
+    fn start(&mut self) -> Result<()> {
+        self.send(ClientMessage::Start)
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.send(ClientMessage::Stop)
+    }
+
+    fn last_frame_id(&self) -> usize {
+        self.last_frame_id.load(Ordering::Acquire)
+    }
+}
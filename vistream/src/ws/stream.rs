@@ -1,16 +1,21 @@
-use crate::camera::{FrameSource, Locate, Worker, Camera, CameraConfig};
-use crate::frame::{PixelFormat, MJPG, Pixelate};
+use crate::camera::{FrameSource, Locate, Worker};
+use crate::frame::{PixelFormat, Pixelate};
+use crate::encode::{Encodable, Encoding};
 use crate::error::{Result, Error};
 use vistream_protocol::stream::{ClientMessage, Status, Frame as ProtoFrame};
 
 use std::net::{TcpListener, SocketAddr, TcpStream};
-use std::io::{self, Write, Read};
-// use std::sync::{Arc, RwLock};
+use std::io;
 use std::sync::atomic::{Ordering};
+use std::time::Duration;
 
+use serde::{Serialize};
 use tungstenite as ws;
 use tungstenite::{WebSocket};
 
+// Slow clients get this long to finish the handshake before they're dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+
 #[allow(dead_code)]
 struct Connection {
     socket: WebSocket<TcpStream>,
@@ -55,6 +60,79 @@ impl Connection {
         self.healthy && self.active
     }
 
+    /// Does the WebSocket handshake on a freshly accepted socket. Anything that isn't a
+    /// WebSocket client is just dropped, rather than taking the whole stream down with it.
+    fn handshake(stream: TcpStream) -> Option<Connection> {
+        stream.set_nonblocking(false).ok()?;
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).ok()?;
+        let socket = ws::accept(stream).ok()?;
+        socket.get_ref().set_read_timeout(Some(Duration::from_nanos(500))).ok()?;
+        Some(Connection::new(socket))
+    }
+
+    /// The next control message from the client, if it's sent one. Clients can send either
+    /// the name of a `ClientMessage` as text ("start", "stop", "disconnect", "status"), or
+    /// its id as a single binary byte.
+    fn poll_message(&mut self) -> Option<ClientMessage> {
+        match self.read() {
+            Ok(ws::Message::Text(text)) => match text.trim().to_ascii_lowercase().as_str() {
+                "start" => Some(ClientMessage::Start),
+                "stop" => Some(ClientMessage::Stop),
+                "disconnect" => Some(ClientMessage::Disconnect),
+                "status" => Some(ClientMessage::Status),
+                _ => None,
+            },
+            Ok(ws::Message::Binary(data)) if data.len() == 1 => ClientMessage::from_id(data[0]),
+            Ok(ws::Message::Close(_)) => Some(ClientMessage::Disconnect),
+            // pings get answered by tungstenite itself
+            Ok(_) => None,
+            Err(Error::WebSocket(ws::Error::Io(e)))
+                if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => None,
+            Err(_) => {
+                self.poison();
+                None
+            }
+        }
+    }
+
+    /// Handles whatever the client has sent since last time, with `status` filling in the
+    /// stream-specific parts of a status reply.
+    fn handle_messages(&mut self, status: &Status) {
+        while let Some(msg) = self.poll_message() {
+            match msg {
+                ClientMessage::Start => self.activate(),
+                ClientMessage::Stop => self.deactivate(),
+                ClientMessage::Disconnect => self.poison(),
+                ClientMessage::Status => {
+                    let status = Status {
+                        enabled: self.is_active(),
+                        healthy: self.is_healthy(),
+                        ..status.clone()
+                    };
+                    match serde_json::to_string(&status) {
+                        Ok(json) => self.send_text(json),
+                        Err(_) => self.poison(),
+                    }
+                }
+            }
+            if !self.is_healthy() {
+                break;
+            }
+        }
+    }
+
+    fn send_text<S: AsRef<str>>(&mut self, payload: S) {
+        if self.write_text(payload).is_err() {
+            self.poison();
+        }
+    }
+
+    fn send_bin<B: AsRef<[u8]>>(&mut self, payload: B) {
+        if self.write_bin(payload).is_err() {
+            self.poison();
+        }
+    }
+
     fn can_read(&self) -> bool {
         self.socket.can_read()
     }
@@ -64,57 +142,111 @@ impl Connection {
     }
     
     fn write_text<S: AsRef<str>>(&mut self, payload: S) -> Result<()> {
-        self.socket.write(payload.as_ref().into()).map_err(|e| e.into())
+        self.socket.send(payload.as_ref().into()).map_err(|e| e.into())
     }
 
     fn write_bin<B: AsRef<[u8]>>(&mut self, payload: B) -> Result<()> {
-        self.socket.write(payload.as_ref().into()).map_err(|e| e.into())
+        self.socket.send(payload.as_ref().into()).map_err(|e| e.into())
     }
 
 }
 
-// impl Write for Connection {
-//     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-//         self.socket.write(buf)
-//     }
-//     fn flush(&mut self) -> io::Result<()> {
-//         self.socket.flush()
-//     }
-// }
-// 
-// impl Read for Connection {
-//     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-//         self.socket.read(buf)
-//     }
-// }
+fn accept_connections(listener: &TcpListener, connections: &mut Vec<Connection>) -> Result<()> {
+    loop {
+        match listener.accept() {
+            Ok((stream, _addr)) => {
+                if let Some(conn) = Connection::handshake(stream) {
+                    connections.push(conn);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
 
+/// Streams frames to WebSocket clients. Each frame is a binary message holding the same
+/// msgpack `Frame` that `FrameStream` sends. Clients control the stream the same way too,
+/// see `Connection::poll_message`, and get status replies as JSON text messages.
 pub struct WSFrameStream {
     worker: Worker,
 }
 
 impl WSFrameStream {
+    /// Streams frames exactly as the source produces them.
     pub fn launch<S, F>(addr: SocketAddr, source: S) -> Result<WSFrameStream>
-    where S: FrameSource<F> + Send + 'static, 
-          F: PixelFormat {
+    where S: FrameSource<F> + Send + 'static,
+          F: Encodable {
+        Self::launch_with_encoding(addr, source, F::native_encoding())
+    }
+
+    pub fn launch_with_encoding<S, F>(addr: SocketAddr, source: S, encoding: Encoding) -> Result<WSFrameStream>
+    where S: FrameSource<F> + Send + 'static,
+          F: Encodable {
+        if !F::supports(encoding) {
+            return Err(Error::UnsupportedEncoding(encoding));
+        }
         let socket = TcpListener::bind(addr)?;
         socket.set_nonblocking(true)?;
         let worker = Worker::spawn(move |kill_flag| {
             let mut source = source;
             let mut connections = Vec::new();
+            let mut last_frame_id = None;
 
             while !kill_flag.load(Ordering::Acquire) {
-                match socket.accept() {
-                    Ok((stream, _addr)) => {
-                        let ws = ws::accept(stream).map_err(|_| Error::Handshake)?;
-                        connections.push(Connection::new(ws))
+                accept_connections(&socket, &mut connections)?;
+
+                let status = Status {
+                    enabled: false,
+                    healthy: true,
+                    framerate: 0.0,
+                    quality: source.encoder_quality(),
+                };
+                for conn in connections.iter_mut() {
+                    conn.handle_messages(&status);
+                }
+
+                // prune connections
+                connections.retain(|conn| conn.is_healthy());
+
+                if !connections.iter().any(|c| c.is_active()) {
+                    std::thread::sleep(std::time::Duration::from_millis(16)); // ~1 frame @ 60fps
+                    continue;
+                }
+
+                // Sources hand back the same frame until there's a new one, which clients
+                // don't need to get twice.
+                let frame = match source.get_frame()? {
+                    Some(frame) if last_frame_id != Some(source.last_frame_id()) => frame,
+                    _ => {
+                        std::thread::sleep(std::time::Duration::from_millis(1));
+                        continue;
                     }
-                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {/* no-op */}
-                    Err(e) => {return Err(e.into());}
+                };
+                last_frame_id = Some(source.last_frame_id());
+                let mut data = Vec::new();
+                if let Err(e) = F::encode(&frame, encoding, &mut data) {
+                    // One bad frame shouldn't end the stream for everyone.
+                    eprintln!("skipping frame that couldn't be encoded: {}", e);
+                    continue;
+                }
+                let frame = ProtoFrame {
+                    width: frame.width() as u32,
+                    height: frame.height() as u32,
+                    data,
+                    encoding,
+                    keyframe: F::is_keyframe(&frame),
+                    format: F::try_proto_format(),
+                };
+                let mut buf = Vec::with_capacity(frame.data.len() + 20);
+                frame.serialize(&mut rmp_serde::Serializer::new(&mut buf)).map_err(|_| Error::Unknown)?;
+
+                for conn in connections.iter_mut().filter(|c| c.is_active()) {
+                    conn.send_bin(&buf);
                 }
             }
-            
 
-            todo!()
+            Ok(())
         });
         Ok(WSFrameStream {
             worker,
@@ -128,3 +260,67 @@ impl WSFrameStream {
         }
     }
 }
+
+/// Streams locations to WebSocket clients, as text messages holding a JSON array of
+/// `LocationData`. Controlled the same way as a `WSFrameStream`.
+pub struct WSLocateStream {
+    worker: Worker,
+}
+
+impl WSLocateStream {
+    pub fn launch<F, S, L>(addr: SocketAddr, source: S, locator: L) -> Result<WSLocateStream>
+    where F: PixelFormat,
+          S: FrameSource<F> + Send + 'static,
+          L: Locate<F, S> + Send + 'static {
+        let socket = TcpListener::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        let worker = Worker::spawn(move |kill_flag| {
+            let mut source = source;
+            let mut locator = locator;
+            let mut connections = Vec::new();
+            let status = Status {
+                enabled: false,
+                healthy: true,
+                framerate: 0.0,
+                quality: None,
+            };
+
+            while !kill_flag.load(Ordering::Acquire) {
+                accept_connections(&socket, &mut connections)?;
+
+                for conn in connections.iter_mut() {
+                    conn.handle_messages(&status);
+                }
+
+                // prune connections
+                connections.retain(|conn| conn.is_healthy());
+
+                if !connections.iter().any(|c| c.is_active()) {
+                    std::thread::sleep(std::time::Duration::from_millis(16)); // ~1 frame @ 60fps
+                    continue;
+                }
+
+                let Some(locs) = locator.locate(&mut source)? else {
+                    continue;
+                };
+                let json = serde_json::to_string(&locs).map_err(|_| Error::Unknown)?;
+
+                for conn in connections.iter_mut().filter(|c| c.is_active()) {
+                    conn.send_text(&json);
+                }
+            }
+
+            Ok(())
+        });
+        Ok(WSLocateStream {
+            worker,
+        })
+    }
+
+    pub fn stop(mut self) -> Result<()> {
+        match self.worker.join() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}